@@ -0,0 +1,38 @@
+-- The cellar schema, as previously set up by hand from kroeg-cellar's schema/db.sql. Everything is
+--  created only if missing, so databases that were set up that way can be migrated as-is.
+
+create table if not exists attribute (
+    id serial primary key,
+    url text not null unique
+);
+
+create table if not exists quad (
+    id serial primary key,
+    quad_id integer not null references attribute on delete cascade,
+    subject_id integer not null references attribute on delete cascade,
+    predicate_id integer not null references attribute on delete cascade,
+
+    attribute_id integer references attribute on delete cascade,
+    object text,
+    type_id integer references attribute on delete cascade,
+    language text
+);
+
+create index if not exists quad_quad_id on quad (quad_id);
+create index if not exists quad_subject_id on quad (subject_id);
+create index if not exists quad_attribute_id on quad (attribute_id);
+
+create table if not exists collection_item (
+    id serial primary key,
+    collection_id integer not null references attribute on delete cascade,
+    object_id integer not null references attribute on delete cascade,
+    unique (collection_id, object_id)
+);
+
+create index if not exists collection_item_object_id on collection_item (object_id);
+
+create table if not exists queue_item (
+    id serial primary key,
+    event text not null,
+    data text not null
+);
@@ -0,0 +1,19 @@
+create table if not exists entities (
+    id text primary key not null,
+    data text not null
+);
+
+create table if not exists collection_items (
+    id integer primary key autoincrement,
+    collection text not null,
+    object text not null,
+    unique (collection, object)
+);
+
+create index if not exists collection_items_object on collection_items (object);
+
+create table if not exists queue_items (
+    id integer primary key autoincrement,
+    event text not null,
+    data text not null
+);
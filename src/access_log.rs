@@ -0,0 +1,370 @@
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::logging;
+use crate::service::client_address;
+use futures::stream::Stream;
+use http::Response;
+use http_service::Body;
+use kroeg_server::{router::RequestHandler, ServerError};
+use kroeg_tap::Context;
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Instant;
+
+enum Sink {
+    Stdout,
+    File(File),
+}
+
+fn open_file(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+struct AccessLogState {
+    config: AccessLogConfig,
+    sink: Mutex<Sink>,
+    reopen: Arc<AtomicBool>,
+}
+
+/// Where finished requests get written to. A log file is reopened after a SIGHUP, so it can be
+///  rotated from under the server.
+#[derive(Clone)]
+pub struct AccessLog(Arc<AccessLogState>);
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> io::Result<AccessLog> {
+        let sink = match &config.path {
+            Some(path) => Sink::File(open_file(path)?),
+            None => Sink::Stdout,
+        };
+
+        let reopen = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::SIGHUP, reopen.clone())?;
+
+        Ok(AccessLog(Arc::new(AccessLogState {
+            config: config.clone(),
+            sink: Mutex::new(sink),
+            reopen,
+        })))
+    }
+
+    fn write(&self, line: &str) {
+        let mut sink = self.0.sink.lock().unwrap();
+
+        if self.0.reopen.swap(false, Ordering::SeqCst) {
+            if let Some(path) = &self.0.config.path {
+                match open_file(path) {
+                    Ok(file) => *sink = Sink::File(file),
+                    Err(e) => log::error!("Failed to reopen access log {}: {}", path, e),
+                }
+            }
+        }
+
+        let result = match &mut *sink {
+            Sink::Stdout => writeln!(io::stdout().lock(), "{}", line),
+            Sink::File(file) => writeln!(file, "{}", line),
+        };
+
+        if let Err(e) = result {
+            log::error!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+struct Entry {
+    remote: String,
+    user: Option<String>,
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    route: Option<String>,
+    status: u16,
+    size: usize,
+    duration: f64,
+    request_id: Option<String>,
+}
+
+impl Entry {
+    fn render(&self, format: AccessLogFormat) -> String {
+        let common = || {
+            format!(
+                "{} - {} [{}] \"{} {} {}\" {} {}",
+                self.remote,
+                self.user.as_ref().map(String::as_str).unwrap_or("-"),
+                chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                self.method,
+                self.target,
+                self.version,
+                self.status,
+                self.size
+            )
+        };
+
+        match format {
+            AccessLogFormat::Common => common(),
+
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                common(),
+                self.referer.as_ref().map(String::as_str).unwrap_or("-"),
+                self.user_agent.as_ref().map(String::as_str).unwrap_or("-")
+            ),
+
+            AccessLogFormat::Json => json!({
+                "timestamp": chrono::Utc::now()
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                "remote": self.remote,
+                "user": self.user,
+                "method": self.method,
+                "path": self.target,
+                "version": self.version,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "route": self.route,
+                "status": self.status,
+                "size": self.size,
+                "duration_ms": self.duration,
+                "request_id": self.request_id,
+            })
+            .to_string(),
+        }
+    }
+}
+
+fn header(request: &http_service::Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// What is only known once a request reaches its route: which route that is, who sent it and the
+///  ID it is handled under.
+#[derive(Default)]
+struct RouteDetails {
+    route: Option<String>,
+    user: Option<String>,
+    request_id: Option<String>,
+}
+
+/// Shared through the request's extensions, so the route's handler can fill it in for the
+///  entry `FrontService` writes.
+#[derive(Clone, Default)]
+struct Details(Arc<Mutex<RouteDetails>>);
+
+/// Records the route a request was answered by, for requests that never reach a handler.
+pub fn set_route(request: &http_service::Request, route: &str) {
+    if let Some(details) = request.extensions().get::<Details>() {
+        details.0.lock().unwrap().route = Some(route.to_owned());
+    }
+}
+
+impl AccessLog {
+    /// Starts the entry for a request that is about to be handled. It gets written once the
+    ///  response to it has been sent, see `PendingEntry::finish`.
+    pub fn start(&self, request: &mut http_service::Request) -> PendingEntry {
+        let details = Details::default();
+        request.extensions_mut().insert(details.clone());
+
+        let entry = Entry {
+            remote: client_address(request)
+                .map(|address| address.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            user: None,
+            method: request.method().to_string(),
+            target: request
+                .uri()
+                .path_and_query()
+                .map(|target| target.as_str().to_owned())
+                .unwrap_or_else(|| "/".to_owned()),
+            version: format!("{:?}", request.version()),
+            referer: header(request, "Referer"),
+            user_agent: header(request, "User-Agent"),
+            route: None,
+            status: 0,
+            size: 0,
+            duration: 0.0,
+            request_id: None,
+        };
+
+        PendingEntry {
+            entry,
+            details,
+            start: Instant::now(),
+            log: self.clone(),
+        }
+    }
+}
+
+/// An access log entry for a request that is still being handled.
+pub struct PendingEntry {
+    entry: Entry,
+    details: Details,
+    start: Instant,
+    log: AccessLog,
+}
+
+impl PendingEntry {
+    fn complete(mut self, status: u16) -> (Entry, Instant, AccessLog) {
+        let details = std::mem::take(&mut *self.details.0.lock().unwrap());
+
+        self.entry.status = status;
+        self.entry.route = details.route;
+        self.entry.user = details.user;
+        self.entry.request_id = details.request_id;
+
+        (self.entry, self.start, self.log)
+    }
+
+    /// Takes the final response, and writes the entry once its body has been sent.
+    pub fn finish(self, response: http_service::Response) -> http_service::Response {
+        let (parts, body) = response.into_parts();
+        let (entry, start, log) = self.complete(parts.status.as_u16());
+
+        let body = LoggedBody {
+            body,
+            entry: Some(entry),
+            start,
+            log,
+        };
+
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+
+    /// Writes the entry for a request that got no response at all.
+    pub fn fail(self) {
+        let (mut entry, start, log) = self.complete(500);
+        entry.duration = start.elapsed().as_secs_f64() * 1000.0;
+        log.write(&entry.render(log.0.config.format));
+    }
+}
+
+/// Wraps a route's handler, telling the access log which route answered the request, and who sent
+///  it. The entry itself is written by `FrontService`, once the final response is known.
+pub struct AccessLogHandler {
+    inner: Box<dyn RequestHandler>,
+    route: String,
+}
+
+impl AccessLogHandler {
+    pub fn new(inner: Box<dyn RequestHandler>, route: String) -> AccessLogHandler {
+        AccessLogHandler { inner, route }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for AccessLogHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let details = request.extensions().get::<Details>().cloned();
+        if let Some(details) = &details {
+            let mut details = details.0.lock().unwrap();
+            details.route = Some(self.route.clone());
+            details.request_id = logging::request_id();
+        }
+
+        let result = self.inner.run(context, request).await;
+
+        if let Some(details) = &details {
+            if context.user.subject != "anonymous" {
+                details.0.lock().unwrap().user = Some(context.user.subject.clone());
+            }
+        }
+
+        result
+    }
+}
+
+/// A response body that counts the bytes sent, and writes the request to the access log once it
+///  is done. That is also when a client that disconnected early makes the server drop it.
+struct LoggedBody {
+    body: Body,
+    entry: Option<Entry>,
+    start: Instant,
+    log: AccessLog,
+}
+
+impl Stream for LoggedBody {
+    type Item = <Body as Stream>::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let chunk = Pin::new(&mut this.body).poll_next(cx);
+
+        if let (Poll::Ready(Some(Ok(chunk))), Some(entry)) = (&chunk, &mut this.entry) {
+            entry.size += chunk.len();
+        }
+
+        chunk
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.duration = self.start.elapsed().as_secs_f64() * 1000.0;
+            self.log.write(&entry.render(self.log.0.config.format));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    fn entry() -> Entry {
+        Entry {
+            remote: "192.0.2.1".to_owned(),
+            user: None,
+            method: "GET".to_owned(),
+            target: "/".to_owned(),
+            version: "HTTP/1.1".to_owned(),
+            referer: None,
+            user_agent: None,
+            route: Some("GET /".to_owned()),
+            status: 200,
+            size: 0,
+            duration: 0.0,
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn logs_the_size_once_the_body_is_sent() {
+        let path = std::env::temp_dir().join(format!("kroeg-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let log = AccessLog::open(&AccessLogConfig {
+            format: AccessLogFormat::Common,
+            path: Some(path.to_str().unwrap().to_owned()),
+        })
+        .unwrap();
+
+        let body = Body::from_stream(LoggedBody {
+            body: Body::from("hello world"),
+            entry: Some(entry()),
+            start: Instant::now(),
+            log,
+        });
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(block_on(body.into_vec()).unwrap(), b"hello world");
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        assert!(line.starts_with("192.0.2.1 - - ["));
+        assert!(line.ends_with("\"GET / HTTP/1.1\" 200 11\n"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
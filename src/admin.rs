@@ -0,0 +1,382 @@
+use crate::database::DatabasePool;
+use crate::entity::parse_document;
+use crate::user::{create_actor, create_auth};
+use http::{Method, Response};
+use http_service::Body;
+use kroeg_server::{
+    config::ServerConfig, router::RequestHandler, router::Route, store::RetrievingEntityStore,
+    ServerError,
+};
+use kroeg_tap::{Context, StoreError};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+pub const PREFIX: &str = "/-/admin/";
+
+const DEFAULT_QUEUE_LIMIT: u32 = 50;
+const DEFAULT_COLLECTION_LIMIT: u32 = 50;
+const MAX_COLLECTION_LIMIT: u32 = 1000;
+
+fn respond(status: u16, body: Value) -> http_service::Response {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error(status: u16, message: &str) -> http_service::Response {
+    respond(status, json!({ "error": message }))
+}
+
+/// Why an admin request failed: the request itself was wrong, or the store couldn't handle it.
+enum AdminError {
+    BadRequest(String),
+    Store(StoreError),
+}
+
+impl From<StoreError> for AdminError {
+    fn from(e: StoreError) -> AdminError {
+        AdminError::Store(e)
+    }
+}
+
+fn bad_request(e: impl std::fmt::Display) -> AdminError {
+    AdminError::BadRequest(e.to_string())
+}
+
+#[derive(Deserialize)]
+struct CreateActor {
+    id: String,
+    username: Option<String>,
+    name: Option<String>,
+}
+
+/// The admin API, for everything the CLI can do to a running instance. Only actors listed in
+///  `admins` may use it.
+///
+/// - `GET entity?id=ID[&remote=true]` and `POST entity?id=ID`, with the JSON-LD as body
+/// - `GET collection?id=ID[&limit=N][&cursor=NEXT]`, one page of items, newest first
+/// - `POST collection/add?id=ID&item=ITEM`, `POST collection/del?...`
+/// - `POST actor`, with `{"id", "username", "name"}` as body
+/// - `POST token?id=ID`, issuing a bearer token for an actor
+/// - `GET queue[?limit=N]`, listing the delivery queue without taking anything out
+pub struct AdminHandler {
+    config: ServerConfig,
+    pool: DatabasePool,
+}
+
+impl AdminHandler {
+    pub fn routes(config: &ServerConfig, pool: &DatabasePool) -> Vec<Route> {
+        let handler = || AdminHandler {
+            config: config.clone(),
+            pool: pool.clone(),
+        };
+
+        vec![
+            Route::get_prefix(PREFIX, handler()),
+            Route::post_prefix(PREFIX, handler()),
+        ]
+    }
+
+    async fn dispatch(
+        &self,
+        context: &mut Context<'_, '_>,
+        method: &Method,
+        path: &str,
+        query: &HashMap<String, String>,
+        body: Vec<u8>,
+    ) -> Result<http_service::Response, AdminError> {
+        let param = |name: &str| {
+            query
+                .get(name)
+                .cloned()
+                .ok_or_else(|| bad_request(format!("missing parameter {}", name)))
+        };
+
+        match (method, path) {
+            (&Method::GET, "entity") => {
+                let local = query.get("remote").map(String::as_str) != Some("true");
+                let mut store = RetrievingEntityStore::new(
+                    &mut *context.entity_store,
+                    self.config.domain.to_owned(),
+                );
+
+                match store.get(param("id")?, local).await? {
+                    Some(entity) => Ok(respond(200, entity.to_json())),
+                    None => Ok(error(404, "entity not found")),
+                }
+            }
+
+            (&Method::POST, "entity") => {
+                let id = param("id")?;
+                let data: Value = serde_json::from_slice(&body).map_err(bad_request)?;
+                let mut item = parse_document(&id, data).await.map_err(bad_request)?;
+                context.entity_store.put(id, &mut item).await?;
+
+                Ok(respond(200, item.to_json()))
+            }
+
+            (&Method::GET, "collection") => {
+                let limit = match query.get("limit") {
+                    Some(limit) => limit.parse().map_err(bad_request)?,
+                    None => DEFAULT_COLLECTION_LIMIT,
+                };
+                if limit == 0 || limit > MAX_COLLECTION_LIMIT {
+                    return Err(bad_request(format!(
+                        "limit must be between 1 and {}",
+                        MAX_COLLECTION_LIMIT
+                    )));
+                }
+
+                let page = context
+                    .entity_store
+                    .read_collection(param("id")?, Some(limit), query.get("cursor").cloned())
+                    .await?;
+
+                // A short page is the last one.
+                let next = if page.items.len() < limit as usize {
+                    None
+                } else {
+                    page.before
+                };
+
+                Ok(respond(200, json!({ "items": page.items, "next": next })))
+            }
+
+            (&Method::POST, "collection/add") => {
+                context
+                    .entity_store
+                    .insert_collection(param("id")?, param("item")?)
+                    .await?;
+
+                Ok(respond(200, json!({})))
+            }
+
+            (&Method::POST, "collection/del") => {
+                context
+                    .entity_store
+                    .remove_collection(param("id")?, param("item")?)
+                    .await?;
+
+                Ok(respond(200, json!({})))
+            }
+
+            (&Method::POST, "actor") => {
+                let actor: CreateActor = serde_json::from_slice(&body).map_err(bad_request)?;
+                create_actor(
+                    &self.config,
+                    context.entity_store,
+                    context.queue_store,
+                    actor.id.to_owned(),
+                    actor.username.as_ref().map(String::as_str),
+                    actor.name.as_ref().map(String::as_str),
+                )
+                .await?;
+
+                Ok(respond(200, json!({ "id": actor.id })))
+            }
+
+            (&Method::POST, "token") => {
+                let id = param("id")?;
+                if context
+                    .entity_store
+                    .get(id.to_owned(), true)
+                    .await?
+                    .is_none()
+                {
+                    return Ok(error(404, "actor not found"));
+                }
+
+                let token = create_auth(context.entity_store, id.to_owned()).await?;
+
+                Ok(respond(200, json!({ "id": id, "token": token })))
+            }
+
+            (&Method::GET, "queue") => {
+                let limit = match query.get("limit") {
+                    Some(limit) => limit.parse().map_err(bad_request)?,
+                    None => DEFAULT_QUEUE_LIMIT,
+                };
+
+                let (depth, items) = self.pool.queue_snapshot(limit).await?;
+                let items: Vec<Value> = items
+                    .into_iter()
+                    .map(|(event, data)| json!({ "event": event, "data": data }))
+                    .collect();
+
+                Ok(respond(200, json!({ "depth": depth, "items": items })))
+            }
+
+            _ => Ok(error(404, "unknown admin endpoint")),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for AdminHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let subject = &context.user.subject;
+        if subject == "anonymous" {
+            return Ok(error(401, "authentication required"));
+        } else if !self.config.admins.iter().any(|admin| admin == subject) {
+            log::warn!("{} tried to use the admin API", subject);
+            return Ok(error(403, "not an admin"));
+        }
+
+        let (parts, body) = request.into_parts();
+        let path = parts
+            .uri
+            .path()
+            .get(PREFIX.len()..)
+            .unwrap_or("")
+            .to_owned();
+        let query: HashMap<String, String> =
+            url::form_urlencoded::parse(parts.uri.query().unwrap_or("").as_bytes())
+                .into_owned()
+                .collect();
+
+        let body = match body.into_vec().await {
+            Ok(body) => body,
+            Err(e) => return Ok(error(400, &format!("failed to read body: {}", e))),
+        };
+
+        log::info!("{} used the admin API: {} {}", subject, parts.method, path);
+
+        match self
+            .dispatch(context, &parts.method, &path, &query, body)
+            .await
+        {
+            Ok(response) => Ok(response),
+            Err(AdminError::BadRequest(message)) => Ok(error(400, &message)),
+            Err(AdminError::Store(e)) => {
+                log::error!("Admin request failed: {}", e);
+                Ok(error(500, &e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DatabaseConfig, KroegConfig};
+    use crate::memory::MemoryStore;
+    use async_std::task::block_on;
+    use kroeg_tap::{EntityStore, User};
+
+    const CONFIG: &str = r#"
+        [database]
+        backend = "memory"
+
+        [server]
+        domain = "https://kroeg.example"
+        name = "Kroeg"
+        description = "Kroeg, running Kroeg"
+        instance_id = 1
+        admins = ["https://kroeg.example/users/admin"]
+    "#;
+
+    fn request(store: &mut MemoryStore, subject: &str, method: &str, path: &str) -> (u16, Value) {
+        let config = KroegConfig::load(CONFIG.as_bytes(), vec![], &[]).unwrap();
+        let pool = DatabasePool::new(DatabaseConfig::Memory);
+        let route = AdminHandler::routes(&config.server, &pool).remove(0);
+
+        let mut queue_store = MemoryStore::new();
+        let mut context = Context {
+            user: User {
+                claims: HashMap::new(),
+                issuer: None,
+                subject: subject.to_owned(),
+                audience: vec![],
+                token_identifier: "test".to_owned(),
+            },
+
+            server_base: "https://kroeg.example".to_owned(),
+            name: "Kroeg".to_owned(),
+            description: "Kroeg".to_owned(),
+            entity_store: store,
+            queue_store: &mut queue_store,
+            instance_id: 1,
+        };
+
+        let request = http::Request::builder()
+            .method(method)
+            .uri(format!("{}{}", PREFIX, path))
+            .body(Body::empty())
+            .unwrap();
+        let response = block_on(route.handler.run(&mut context, request)).unwrap();
+        let status = response.status().as_u16();
+        let body = block_on(response.into_body().into_vec()).unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn only_admins_get_in() {
+        let mut store = MemoryStore::new();
+        let path = "collection?id=https://kroeg.example/collection";
+
+        assert_eq!(request(&mut store, "anonymous", "GET", path).0, 401);
+        assert_eq!(
+            request(&mut store, "https://kroeg.example/users/a", "GET", path).0,
+            403
+        );
+        assert_eq!(
+            request(&mut store, "https://kroeg.example/users/admin", "GET", path).0,
+            200
+        );
+    }
+
+    #[test]
+    fn collections_are_read_a_page_at_a_time() {
+        let mut store = MemoryStore::new();
+        for item in &["a", "b", "c"] {
+            block_on(store.insert_collection(
+                "https://kroeg.example/collection".to_owned(),
+                format!("https://kroeg.example/{}", item),
+            ))
+            .unwrap();
+        }
+
+        let admin = "https://kroeg.example/users/admin";
+        let (status, first) = request(
+            &mut store,
+            admin,
+            "GET",
+            "collection?id=https://kroeg.example/collection&limit=2",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(
+            first["items"],
+            json!(["https://kroeg.example/c", "https://kroeg.example/b"])
+        );
+
+        let next = first["next"].as_str().unwrap();
+        let (_, second) = request(
+            &mut store,
+            admin,
+            "GET",
+            &format!(
+                "collection?id=https://kroeg.example/collection&limit=2&cursor={}",
+                next
+            ),
+        );
+        assert_eq!(second["items"], json!(["https://kroeg.example/a"]));
+        assert_eq!(second["next"], Value::Null);
+
+        let (status, _) = request(
+            &mut store,
+            admin,
+            "GET",
+            "collection?id=https://kroeg.example/collection&limit=100000",
+        );
+        assert_eq!(status, 400);
+    }
+}
@@ -0,0 +1,247 @@
+use chrono::{DateTime, Utc};
+use http::header::HeaderValue;
+use http::{Response, StatusCode};
+use http_service::Body;
+use kroeg_server::{router::RequestHandler, router::Route, ServerError};
+use kroeg_tap::Context;
+
+/// Formats a time the way HTTP headers like Last-Modified expect it.
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+fn etag(body: &[u8]) -> String {
+    let hash = openssl::sha::sha256(body);
+
+    format!(
+        "\"{}\"",
+        base64::encode_config(&hash[..16], base64::URL_SAFE_NO_PAD)
+    )
+}
+
+fn header<'a>(request: &'a http_service::Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+// Whether the client's copy is still current, going by If-None-Match, or If-Modified-Since
+//  without it. If so, returns the ETag the 304 should carry.
+fn not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    last_modified: Option<&HeaderValue>,
+) -> Option<String> {
+    // Compressed responses carry the encoding in their ETag, but stay the same document. A client
+    //  holding a compressed copy gets its own ETag back, so it keeps matching the one it has.
+    let matches = |tag: &&str| {
+        *tag == "*"
+            || *tag == etag
+            || tag.replace("-gzip\"", "\"") == etag
+            || tag.replace("-br\"", "\"") == etag
+    };
+
+    if let Some(if_none_match) = if_none_match {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .find(matches)
+            .map(|tag| if tag == "*" { etag } else { tag }.to_owned());
+    }
+
+    let since = if_modified_since.and_then(parse_http_date);
+    let modified = last_modified
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_http_date);
+
+    match (since, modified) {
+        (Some(since), Some(modified)) if modified <= since => Some(etag.to_owned()),
+        _ => None,
+    }
+}
+
+/// Wraps a route's handler, adding an ETag and Cache-Control to successful responses, and
+///  answering with 304 Not Modified if the client already has the same response.
+pub struct CachingHandler {
+    inner: Box<dyn RequestHandler>,
+    cache_control: HeaderValue,
+}
+
+impl CachingHandler {
+    /// Makes a route cacheable, with `cache_control` as Cache-Control for anonymous requests.
+    pub fn route(route: Route, cache_control: &str) -> Route {
+        let cache_control =
+            HeaderValue::from_str(cache_control).expect("Invalid Cache-Control in config");
+
+        Route {
+            handler: Box::new(CachingHandler {
+                inner: route.handler,
+                cache_control,
+            }),
+            ..route
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for CachingHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let if_none_match = header(&request, "If-None-Match").map(str::to_owned);
+        let if_modified_since = header(&request, "If-Modified-Since").map(str::to_owned);
+
+        let response = self.inner.run(context, request).await?;
+        if response.status() != StatusCode::OK {
+            return Ok(response);
+        }
+
+        // What an authenticated request gets to see may not be public, so shared caches must not
+        //  keep it. This covers every way of authenticating, cookies included.
+        let authenticated = context.user.subject != "anonymous";
+
+        let (mut parts, body) = response.into_parts();
+        let body = match body.into_vec().await {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("Failed to read response body: {}", e);
+                return Ok(Response::builder().status(500).body(Body::empty()).unwrap());
+            }
+        };
+
+        let etag = etag(&body);
+        parts
+            .headers
+            .insert("ETag", HeaderValue::from_str(&etag).unwrap());
+        parts
+            .headers
+            .append("Vary", HeaderValue::from_static("Accept"));
+
+        if !parts.headers.contains_key("Cache-Control") {
+            let cache_control = if authenticated {
+                HeaderValue::from_static("private, no-cache")
+            } else {
+                self.cache_control.clone()
+            };
+
+            parts.headers.insert("Cache-Control", cache_control);
+        }
+
+        let current = not_modified(
+            if_none_match.as_ref().map(String::as_str),
+            if_modified_since.as_ref().map(String::as_str),
+            &etag,
+            parts.headers.get("Last-Modified"),
+        );
+
+        if let Some(etag) = current {
+            parts.status = StatusCode::NOT_MODIFIED;
+            parts.headers.remove("Content-Length");
+            if let Ok(etag) = HeaderValue::from_str(&etag) {
+                parts.headers.insert("ETag", etag);
+            }
+
+            return Ok(Response::from_parts(parts, Body::empty()));
+        }
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use async_std::task::block_on;
+    use kroeg_tap::User;
+    use std::collections::HashMap;
+
+    const ETAG: &str = "\"abc\"";
+
+    struct Hello;
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Hello {
+        async fn run(
+            &self,
+            _: &mut Context<'_, '_>,
+            _: http_service::Request,
+        ) -> Result<http_service::Response, ServerError> {
+            Ok(Response::new(Body::from("hello")))
+        }
+    }
+
+    fn cache_control(subject: &str) -> HeaderValue {
+        let route = CachingHandler::route(Route::get("/", Hello), "public, max-age=60");
+
+        let mut entity_store = MemoryStore::new();
+        let mut queue_store = MemoryStore::new();
+        let mut context = Context {
+            user: User {
+                claims: HashMap::new(),
+                issuer: None,
+                subject: subject.to_owned(),
+                audience: vec![],
+                token_identifier: "test".to_owned(),
+            },
+
+            server_base: "https://kroeg.example".to_owned(),
+            name: "Kroeg".to_owned(),
+            description: "Kroeg".to_owned(),
+            entity_store: &mut entity_store,
+            queue_store: &mut queue_store,
+            instance_id: 1,
+        };
+
+        // No Authorization or Signature header, the way a cookie-authenticated request comes in.
+        let request = http::Request::get("/").body(Body::empty()).unwrap();
+        let response = block_on(route.handler.run(&mut context, request)).unwrap();
+
+        response.headers()["Cache-Control"].clone()
+    }
+
+    #[test]
+    fn authenticated_responses_stay_private() {
+        assert_eq!(cache_control("anonymous"), "public, max-age=60");
+        assert_eq!(
+            cache_control("https://kroeg.example/users/a"),
+            "private, no-cache"
+        );
+    }
+
+    #[test]
+    fn compressed_copies_keep_their_etag() {
+        let check = |tag| not_modified(Some(tag), None, ETAG, None);
+
+        assert_eq!(check("\"abc\""), Some("\"abc\"".to_owned()));
+        assert_eq!(check("\"abc-gzip\""), Some("\"abc-gzip\"".to_owned()));
+        assert_eq!(
+            check("\"old\", W/\"abc-br\""),
+            Some("\"abc-br\"".to_owned())
+        );
+        assert_eq!(check("*"), Some("\"abc\"".to_owned()));
+        assert_eq!(check("\"abd-gzip\""), None);
+    }
+
+    #[test]
+    fn falls_back_to_modification_times() {
+        let last_modified = HeaderValue::from_static("Mon, 07 Oct 2019 12:00:00 GMT");
+        let check = |since| not_modified(None, Some(since), ETAG, Some(&last_modified));
+
+        assert_eq!(
+            check("Mon, 07 Oct 2019 12:00:00 GMT"),
+            Some("\"abc\"".to_owned())
+        );
+        assert_eq!(check("Sun, 06 Oct 2019 12:00:00 GMT"), None);
+    }
+}
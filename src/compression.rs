@@ -0,0 +1,290 @@
+use crate::config::CompressionConfig;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::stream::StreamExt;
+use http::header::HeaderValue;
+use http::{Response, StatusCode};
+use http_service::Body;
+use kroeg_server::{router::RequestHandler, router::Route, ServerError};
+use kroeg_tap::Context;
+use std::io::{self, Read, Write};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut output = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                    writer.write_all(body)?;
+                }
+
+                Ok(output)
+            }
+
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Picks the encoding to use from an Accept-Encoding header, preferring brotli over gzip when the
+///  client likes them equally.
+fn negotiate(accept_encoding: &str, config: &CompressionConfig) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let quality = parts
+            .map(str::trim)
+            .filter(|param| param.starts_with("q="))
+            .filter_map(|param| param[2..].trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        let encodings: &[Encoding] = match name.as_str() {
+            "br" => &[Encoding::Brotli],
+            "gzip" | "x-gzip" => &[Encoding::Gzip],
+            "*" => &[Encoding::Brotli, Encoding::Gzip],
+            _ => &[],
+        };
+
+        for encoding in encodings {
+            let enabled = match encoding {
+                Encoding::Brotli => config.brotli,
+                Encoding::Gzip => config.gzip,
+            };
+
+            let better = match best {
+                Some((current, best_quality)) => {
+                    quality > best_quality
+                        || (quality == best_quality
+                            && *encoding == Encoding::Brotli
+                            && current == Encoding::Gzip)
+                }
+                None => true,
+            };
+
+            if enabled && quality > 0.0 && better {
+                best = Some((*encoding, quality));
+            }
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+fn compressible(content_type: Option<&HeaderValue>) -> bool {
+    let content_type = match content_type.and_then(|value| value.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || essence.ends_with("json")
+        || essence.ends_with("+xml")
+        || essence == "application/xml"
+        || essence == "application/javascript"
+}
+
+/// Wraps a route's handler, compressing large enough responses with the best encoding the
+///  client accepts.
+pub struct CompressionHandler {
+    inner: Box<dyn RequestHandler>,
+    config: CompressionConfig,
+}
+
+impl CompressionHandler {
+    pub fn new(inner: Box<dyn RequestHandler>, config: CompressionConfig) -> CompressionHandler {
+        CompressionHandler { inner, config }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for CompressionHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let encoding = request
+            .headers()
+            .get("Accept-Encoding")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| negotiate(value, &self.config));
+
+        let response = self.inner.run(context, request).await?;
+
+        let skip = response.status() == StatusCode::NO_CONTENT
+            || response.status() == StatusCode::NOT_MODIFIED
+            || response.headers().contains_key("Content-Encoding")
+            || !compressible(response.headers().get("Content-Type"));
+        if skip {
+            return Ok(response);
+        }
+
+        let (mut parts, body) = response.into_parts();
+        parts
+            .headers
+            .append("Vary", HeaderValue::from_static("Accept-Encoding"));
+
+        let body = match body.into_vec().await {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("Failed to read response body: {}", e);
+                return Ok(Response::builder().status(500).body(Body::empty()).unwrap());
+            }
+        };
+
+        let encoding = match encoding {
+            Some(encoding) if body.len() >= self.config.min_size => encoding,
+            _ => return Ok(Response::from_parts(parts, Body::from(body))),
+        };
+
+        let compressed = match encoding.encode(&body) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                log::warn!("Failed to compress response: {}", e);
+                return Ok(Response::from_parts(parts, Body::from(body)));
+            }
+        };
+
+        // The compressed response is a different representation, so it needs its own ETag.
+        let etag = parts
+            .headers
+            .get("ETag")
+            .and_then(|value| value.to_str().ok())
+            .map(|etag| format!("{}-{}\"", etag.trim_end_matches('"'), encoding.name()))
+            .and_then(|etag| HeaderValue::from_str(&etag).ok());
+
+        if let Some(etag) = etag {
+            parts.headers.insert("ETag", etag);
+        }
+
+        parts.headers.remove("Content-Length");
+        parts.headers.insert(
+            "Content-Encoding",
+            HeaderValue::from_static(encoding.name()),
+        );
+
+        Ok(Response::from_parts(parts, Body::from(compressed)))
+    }
+}
+
+fn error(status: u16, message: &'static str) -> http_service::Response {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+// Reads a body of at most `limit` bytes, or returns `None` as soon as it turns out to be longer.
+async fn read_limited(mut body: Body, limit: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+
+    while let Some(chunk) = body.next().await {
+        data.extend_from_slice(&chunk?);
+        if data.len() as u64 > limit {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(data))
+}
+
+/// Wraps a route's handler, decompressing gzipped request bodies before it sees them. Both the
+///  compressed and the decompressed body may be at most `max_size` bytes.
+pub struct DecompressionHandler {
+    inner: Box<dyn RequestHandler>,
+    max_size: u64,
+}
+
+impl DecompressionHandler {
+    pub fn route(route: Route, max_size: u64) -> Route {
+        Route {
+            handler: Box::new(DecompressionHandler {
+                inner: route.handler,
+                max_size,
+            }),
+            ..route
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for DecompressionHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let encoding = match request.headers().get("Content-Encoding") {
+            Some(encoding) => encoding.to_str().unwrap_or("").trim().to_lowercase(),
+            None => return self.inner.run(context, request).await,
+        };
+
+        if encoding == "identity" {
+            return self.inner.run(context, request).await;
+        } else if encoding != "gzip" && encoding != "x-gzip" {
+            return Ok(error(415, "Only gzip request bodies are supported"));
+        }
+
+        let (mut parts, body) = request.into_parts();
+        let body = match read_limited(body, self.max_size).await {
+            Ok(Some(body)) => body,
+            Ok(None) => return Ok(error(413, "Request body is too large")),
+            Err(_) => return Ok(error(400, "Failed to read request body")),
+        };
+
+        // Reading one byte past the limit tells an oversized body apart from one that fits exactly.
+        let mut decompressed = Vec::new();
+        let result = GzDecoder::new(&body[..])
+            .take(self.max_size + 1)
+            .read_to_end(&mut decompressed);
+
+        if result.is_err() {
+            return Ok(error(400, "Invalid gzip request body"));
+        } else if decompressed.len() as u64 > self.max_size {
+            return Ok(error(413, "Decompressed request body is too large"));
+        }
+
+        parts.headers.remove("Content-Encoding");
+        parts.headers.remove("Content-Length");
+
+        let request = http::Request::from_parts(parts, Body::from(decompressed));
+        self.inner.run(context, request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn reads_bodies_up_to_the_limit() {
+        let read = |body: &'static str| block_on(read_limited(Body::from(body), 5)).unwrap();
+
+        assert_eq!(read("hello"), Some(b"hello".to_vec()));
+        assert_eq!(read(""), Some(vec![]));
+        assert_eq!(read("hello!"), None);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::config::CorsConfig;
+use http::header::{HeaderMap, HeaderValue};
+use http::Response;
+use http_service::Body;
+use kroeg_server::{router::RequestHandler, router::Route, ServerError};
+use kroeg_tap::Context;
+use std::sync::{Arc, Mutex};
+
+/// The CORS policy from `[server.cors]`, shared between all routes it applies to.
+#[derive(Clone)]
+pub struct Cors(Arc<CorsConfig>);
+
+impl Cors {
+    pub fn new(config: CorsConfig) -> Cors {
+        Cors(Arc::new(config))
+    }
+
+    fn allow_any(&self) -> bool {
+        self.0.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    // The value for Access-Control-Allow-Origin, if the origin is allowed at all.
+    fn allowed_origin(&self, request: &http_service::Request) -> Option<HeaderValue> {
+        let origin = request.headers().get("Origin")?;
+
+        // Config validation makes sure `*` never comes with credentials.
+        if self.allow_any() {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let allowed = self
+            .0
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.as_bytes() == origin.as_bytes());
+
+        if allowed {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+
+    fn apply(&self, headers: &mut HeaderMap, origin: Option<HeaderValue>) {
+        if !self.allow_any() {
+            headers.append("Vary", HeaderValue::from_static("Origin"));
+        }
+
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return,
+        };
+
+        headers.insert("Access-Control-Allow-Origin", origin);
+
+        if self.0.allow_credentials {
+            headers.insert(
+                "Access-Control-Allow-Credentials",
+                HeaderValue::from_static("true"),
+            );
+        }
+
+        if !self.0.exposed_headers.is_empty() {
+            if let Ok(value) = HeaderValue::from_str(&self.0.exposed_headers.join(", ")) {
+                headers.insert("Access-Control-Expose-Headers", value);
+            }
+        }
+    }
+
+    /// Makes a route send CORS headers.
+    pub fn route(&self, route: Route) -> Route {
+        Route {
+            handler: Box::new(CorsHandler {
+                inner: route.handler,
+                cors: self.clone(),
+            }),
+            ..route
+        }
+    }
+
+    /// Answers preflight requests for every path.
+    pub fn preflight_route(&self) -> Route {
+        Route {
+            content_type: vec![],
+            method: http::Method::OPTIONS,
+            path: "/".to_owned(),
+            is_prefix: true,
+            handler: Box::new(PreflightHandler(self.clone())),
+        }
+    }
+}
+
+/// The CORS headers for a request that failed. `KroegService` turns the error into a response with
+///  the right status and body, and `FrontService`, which puts one of these in every request, adds
+///  the headers to it. Browsers hide responses without them entirely, errors included.
+#[derive(Clone, Default)]
+pub struct ErrorHeaders(Arc<Mutex<Option<HeaderMap>>>);
+
+impl ErrorHeaders {
+    pub fn set(&self, headers: HeaderMap) {
+        *self.0.lock().unwrap() = Some(headers);
+    }
+
+    pub fn apply(&self, response: &mut http_service::Response) {
+        if let Some(headers) = self.0.lock().unwrap().take() {
+            for (name, value) in headers.iter() {
+                response.headers_mut().append(name, value.clone());
+            }
+        }
+    }
+}
+
+struct CorsHandler {
+    inner: Box<dyn RequestHandler>,
+    cors: Cors,
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for CorsHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let origin = self.cors.allowed_origin(&request);
+        let error_headers = request.extensions().get::<ErrorHeaders>().cloned();
+
+        match self.inner.run(context, request).await {
+            Ok(mut response) => {
+                self.cors.apply(response.headers_mut(), origin);
+                Ok(response)
+            }
+
+            Err(e) => {
+                if let Some(error_headers) = error_headers {
+                    let mut headers = HeaderMap::new();
+                    self.cors.apply(&mut headers, origin);
+                    error_headers.set(headers);
+                }
+
+                Err(e)
+            }
+        }
+    }
+}
+
+struct PreflightHandler(Cors);
+
+#[async_trait::async_trait]
+impl RequestHandler for PreflightHandler {
+    async fn run(
+        &self,
+        _: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let config = &(self.0).0;
+        let mut response = Response::builder().status(204).body(Body::empty()).unwrap();
+
+        let origin = self.0.allowed_origin(&request);
+        let method_allowed = request
+            .headers()
+            .get("Access-Control-Request-Method")
+            .map_or(false, |method| {
+                config
+                    .allowed_methods
+                    .iter()
+                    .any(|allowed| allowed.as_bytes() == method.as_bytes())
+            });
+
+        // Leaving out the Access-Control-Allow-* headers is how a preflight says no.
+        let allowed = origin.is_some() && method_allowed;
+        self.0.apply(response.headers_mut(), origin);
+
+        if allowed {
+            let headers = response.headers_mut();
+
+            if let Ok(value) = HeaderValue::from_str(&config.allowed_methods.join(", ")) {
+                headers.insert("Access-Control-Allow-Methods", value);
+            }
+
+            if let Ok(value) = HeaderValue::from_str(&config.allowed_headers.join(", ")) {
+                headers.insert("Access-Control-Allow-Headers", value);
+            }
+
+            headers.insert("Access-Control-Max-Age", HeaderValue::from(config.max_age));
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use async_std::task::block_on;
+    use kroeg_tap::User;
+    use std::collections::HashMap;
+
+    fn cors(origins: &[&str]) -> Cors {
+        Cors::new(CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allowed_methods: vec!["GET".to_owned(), "POST".to_owned()],
+            allowed_headers: vec!["Content-Type".to_owned()],
+            exposed_headers: vec![],
+            allow_credentials: false,
+            max_age: 60,
+        })
+    }
+
+    fn run(handler: &dyn RequestHandler, request: http_service::Request) -> http_service::Response {
+        let mut entity_store = MemoryStore::new();
+        let mut queue_store = MemoryStore::new();
+        let mut context = Context {
+            user: User {
+                claims: HashMap::new(),
+                issuer: None,
+                subject: "anonymous".to_owned(),
+                audience: vec![],
+                token_identifier: "test".to_owned(),
+            },
+
+            server_base: "https://kroeg.example".to_owned(),
+            name: "Kroeg".to_owned(),
+            description: "Kroeg".to_owned(),
+            entity_store: &mut entity_store,
+            queue_store: &mut queue_store,
+            instance_id: 1,
+        };
+
+        block_on(handler.run(&mut context, request)).unwrap()
+    }
+
+    fn preflight(cors: &Cors, origin: &str, method: Option<&str>) -> http_service::Response {
+        let mut request = http::Request::builder();
+        request
+            .method("OPTIONS")
+            .uri("/inbox")
+            .header("Origin", origin);
+        if let Some(method) = method {
+            request.header("Access-Control-Request-Method", method);
+        }
+
+        run(
+            &PreflightHandler(cors.clone()),
+            request.body(Body::empty()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn preflights_check_the_method() {
+        let cors = cors(&["https://app.example"]);
+
+        let allowed = preflight(&cors, "https://app.example", Some("POST"));
+        assert_eq!(
+            allowed.headers()["Access-Control-Allow-Methods"],
+            "GET, POST"
+        );
+        assert_eq!(
+            allowed.headers()["Access-Control-Allow-Origin"],
+            "https://app.example"
+        );
+
+        let refused = preflight(&cors, "https://app.example", Some("DELETE"));
+        assert!(!refused
+            .headers()
+            .contains_key("Access-Control-Allow-Methods"));
+
+        let missing = preflight(&cors, "https://app.example", None);
+        assert!(!missing
+            .headers()
+            .contains_key("Access-Control-Allow-Methods"));
+
+        let other = preflight(&cors, "https://evil.example", Some("POST"));
+        assert!(!other.headers().contains_key("Access-Control-Allow-Origin"));
+        assert!(!other.headers().contains_key("Access-Control-Allow-Methods"));
+    }
+
+    #[test]
+    fn any_origin_gets_a_wildcard() {
+        let response = preflight(&cors(&["*"]), "https://app.example", Some("GET"));
+
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], "*");
+        assert!(!response.headers().contains_key("Vary"));
+    }
+}
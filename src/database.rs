@@ -0,0 +1,563 @@
+use crate::config::{DatabaseConfig, PostgresConfig};
+use crate::logging::{self, TracedStore};
+use crate::memory::MemoryStore;
+use crate::metrics::{MeteredStore, Metrics};
+use crate::pool::{Manager, Pool, Pooled};
+use crate::schema::{self, PostgresSchema, Schema, SqliteSchema};
+use crate::shutdown::{Shutdown, ShutdownQueue};
+use crate::sqlite::{SqliteManager, SqliteStore};
+use futures::channel::oneshot;
+use kroeg_cellar::{CellarConnection, CellarEntityStore};
+use kroeg_server::{LeasedConnection, StorePool};
+use kroeg_tap::{
+    CollectionPointer, EntityStore, QuadQuery, QueueItem, QueueStore, StoreError, StoreItem,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct CellarManager(PostgresConfig);
+
+#[async_trait::async_trait]
+impl Manager for CellarManager {
+    type Connection = CellarConnection;
+
+    async fn connect(&self) -> Result<Self::Connection, StoreError> {
+        let params = self.0.connection_params()?;
+
+        // Cellar opens its own plain TCP connections, so it can't honour a mode that needs TLS.
+        if params.sslmode.requires_tls() {
+            return Err(format!(
+                "sslmode {:?} can't be used for the entity store, as cellar connections don't \
+                 support TLS yet; only the queue statistics and `db` commands use it",
+                params.sslmode
+            )
+            .into());
+        }
+
+        let connection = CellarConnection::connect(
+            &params.server,
+            &params.username,
+            &params.password,
+            &params.database,
+        )
+        .await?;
+
+        Ok(connection)
+    }
+
+    async fn check(&self, connection: &mut Self::Connection) -> Result<(), StoreError> {
+        let mut store = CellarEntityStore::new(connection);
+        store.get("kroeg:health-check".to_owned(), true).await?;
+
+        Ok(())
+    }
+}
+
+/// A handle to a leased PostgreSQL connection. The entity store and queue store of one lease share
+///  the connection, which goes back into the pool once both handles are dropped.
+pub struct CellarStore(Arc<Pooled<CellarManager>>);
+
+#[async_trait::async_trait]
+impl EntityStore for CellarStore {
+    async fn get(&mut self, path: String, local: bool) -> Result<Option<StoreItem>, StoreError> {
+        CellarEntityStore::new(&self.0).get(path, local).await
+    }
+
+    async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
+        CellarEntityStore::new(&self.0).put(path, item).await
+    }
+
+    async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
+        CellarEntityStore::new(&self.0).query(query).await
+    }
+
+    async fn read_collection(
+        &mut self,
+        path: String,
+        count: Option<u32>,
+        cursor: Option<String>,
+    ) -> Result<CollectionPointer, StoreError> {
+        CellarEntityStore::new(&self.0)
+            .read_collection(path, count, cursor)
+            .await
+    }
+
+    async fn find_collection(
+        &mut self,
+        path: String,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        CellarEntityStore::new(&self.0)
+            .find_collection(path, item)
+            .await
+    }
+
+    async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        CellarEntityStore::new(&self.0)
+            .insert_collection(path, item)
+            .await
+    }
+
+    async fn read_collection_inverse(
+        &mut self,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        CellarEntityStore::new(&self.0)
+            .read_collection_inverse(item)
+            .await
+    }
+
+    async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        CellarEntityStore::new(&self.0)
+            .remove_collection(path, item)
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl QueueStore for CellarStore {
+    async fn get_item(&mut self) -> Result<Option<Box<dyn QueueItem + Send>>, StoreError> {
+        CellarEntityStore::new(&self.0).get_item().await
+    }
+
+    async fn mark_success(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        CellarEntityStore::new(&self.0).mark_success(item).await
+    }
+
+    async fn mark_failure(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        CellarEntityStore::new(&self.0).mark_failure(item).await
+    }
+
+    async fn add(&mut self, event: String, data: String) -> Result<(), StoreError> {
+        CellarEntityStore::new(&self.0).add(event, data).await
+    }
+}
+
+type StatsJob = Box<dyn FnOnce(&PostgresConfig, &mut Option<postgres::Connection>) + Send>;
+
+/// Reads statistics about the delivery queue, which the store API doesn't offer. Cellar
+///  connections can't run queries of their own, so this keeps a single blocking connection on a
+///  thread of its own, started on first use. Callers only wait for the answer, without blocking.
+#[derive(Clone)]
+pub struct CellarQueueStats {
+    config: PostgresConfig,
+    sender: Arc<Mutex<Option<Sender<StatsJob>>>>,
+}
+
+impl CellarQueueStats {
+    fn new(config: PostgresConfig) -> CellarQueueStats {
+        CellarQueueStats {
+            config,
+            sender: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn spawn(&self) -> Result<Sender<StatsJob>, StoreError> {
+        let (sender, receiver) = mpsc::channel::<StatsJob>();
+        let config = self.config.clone();
+
+        thread::Builder::new()
+            .name("queue-stats".to_owned())
+            .spawn(move || {
+                let mut connection = None;
+                for job in receiver {
+                    job(&config, &mut connection);
+                }
+            })?;
+
+        Ok(sender)
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut postgres::Connection) -> Result<T, StoreError> + Send + 'static,
+    ) -> Result<T, StoreError> {
+        let (reply, response) = oneshot::channel();
+
+        let job: StatsJob = Box::new(move |config, connection| {
+            let result = match connection {
+                Some(connection) => query(connection),
+                None => schema::connect_postgres(config)
+                    .and_then(|opened| query(connection.get_or_insert(opened))),
+            };
+
+            // A failed connection is opened again for the next query.
+            if result.is_err() {
+                *connection = None;
+            }
+
+            let _ = reply.send(result);
+        });
+
+        {
+            let mut sender = self.sender.lock().unwrap();
+            if sender.is_none() {
+                *sender = Some(self.spawn()?);
+            }
+
+            if sender.as_ref().unwrap().send(job).is_err() {
+                *sender = None;
+                return Err("the queue statistics thread stopped".into());
+            }
+        }
+
+        response
+            .await
+            .map_err(|_| StoreError::from("the queue statistics thread stopped"))?
+    }
+
+    pub async fn depth(&self) -> Result<u64, StoreError> {
+        self.run(|connection| {
+            let rows = connection.query("select count(*) from queue_item", &[])?;
+            let count: i64 = rows.get(0).get(0);
+
+            Ok(count as u64)
+        })
+        .await
+    }
+
+    pub async fn snapshot(&self, limit: u32) -> Result<(u64, Vec<(String, String)>), StoreError> {
+        self.run(move |connection| {
+            let rows = connection.query("select count(*) from queue_item", &[])?;
+            let count: i64 = rows.get(0).get(0);
+
+            let rows = connection.query(
+                "select event, data from queue_item order by id asc limit $1",
+                &[&i64::from(limit)],
+            )?;
+            let items = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+
+            Ok((count as u64, items))
+        })
+        .await
+    }
+}
+
+async fn maintain<M: Manager>(pool: Pool<M>) {
+    loop {
+        if let Err(e) = pool.maintain().await {
+            log::warn!("Failed to maintain database pool: {}", e);
+        }
+
+        async_std::task::sleep(MAINTENANCE_INTERVAL).await;
+    }
+}
+
+pub enum DatabaseConnection {
+    PostgreSQL(
+        MeteredStore<TracedStore<CellarStore>>,
+        ShutdownQueue<MeteredStore<TracedStore<CellarStore>>>,
+    ),
+    Memory(
+        MeteredStore<TracedStore<MemoryStore>>,
+        ShutdownQueue<MeteredStore<TracedStore<MemoryStore>>>,
+    ),
+    Sqlite(
+        MeteredStore<TracedStore<SqliteStore>>,
+        ShutdownQueue<MeteredStore<TracedStore<SqliteStore>>>,
+    ),
+}
+
+impl LeasedConnection for DatabaseConnection {
+    fn get(&mut self) -> (&mut dyn EntityStore, &mut dyn QueueStore) {
+        match self {
+            DatabaseConnection::PostgreSQL(left, right) => (left, right),
+            DatabaseConnection::Memory(left, right) => (left, right),
+            DatabaseConnection::Sqlite(left, right) => (left, right),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Backend {
+    PostgreSQL(Pool<CellarManager>, CellarQueueStats),
+    Memory(MemoryStore),
+    Sqlite(Pool<SqliteManager>),
+}
+
+/// Hands out database connections for the configured backend. Clones share the same connections.
+#[derive(Clone)]
+pub struct DatabasePool(Backend, Shutdown, Metrics);
+
+impl DatabasePool {
+    pub fn new(config: DatabaseConfig) -> DatabasePool {
+        let backend = match config {
+            DatabaseConfig::PostgreSQL(config) => Backend::PostgreSQL(
+                Pool::new(CellarManager(config.clone()), config.pool.clone()),
+                CellarQueueStats::new(config),
+            ),
+            DatabaseConfig::Memory => Backend::Memory(MemoryStore::new()),
+            DatabaseConfig::Sqlite { path, pool } => {
+                Backend::Sqlite(Pool::new(SqliteManager(path), pool))
+            }
+        };
+
+        DatabasePool(backend, Shutdown::new(), Metrics::new())
+    }
+
+    /// Makes the queue stores of every connection leased from this pool follow `shutdown`.
+    pub fn with_shutdown(self, shutdown: Shutdown) -> DatabasePool {
+        DatabasePool(self.0, shutdown, self.2)
+    }
+
+    /// Records lease times and store operations of connections from this pool into `metrics`.
+    pub fn with_metrics(self, metrics: Metrics) -> DatabasePool {
+        DatabasePool(self.0, self.1, metrics)
+    }
+
+    /// Periodically closes idle connections and keeps `min_idle` connections open. This never
+    ///  returns, so it should be spawned as a separate task.
+    pub async fn maintain(self) {
+        match self.0 {
+            Backend::PostgreSQL(pool, _) => maintain(pool).await,
+            Backend::Sqlite(pool) => maintain(pool).await,
+            Backend::Memory(_) => (),
+        }
+    }
+
+    /// Opens a connection for managing the database schema, if this backend has one. A read-only
+    ///  one doesn't create a missing SQLite file.
+    pub fn schema(&self, read_only: bool) -> Result<Option<Box<dyn Schema>>, StoreError> {
+        match &self.0 {
+            Backend::PostgreSQL(pool, _) => {
+                Ok(Some(Box::new(PostgresSchema::connect(&pool.manager().0)?)))
+            }
+
+            Backend::Memory(_) => Ok(None),
+            Backend::Sqlite(pool) if read_only => Ok(Some(Box::new(SqliteSchema::open_read_only(
+                &pool.manager().0,
+            )?))),
+            Backend::Sqlite(pool) => Ok(Some(Box::new(SqliteSchema::open(&pool.manager().0)?))),
+        }
+    }
+
+    /// Counts the items waiting in the delivery queue.
+    pub async fn queue_depth(&self) -> Result<u64, StoreError> {
+        match &self.0 {
+            Backend::PostgreSQL(_, stats) => stats.depth().await,
+            Backend::Memory(memory) => Ok(memory.queue_depth()),
+            Backend::Sqlite(pool) => SqliteStore::new(pool.get().await?).queue_depth().await,
+        }
+    }
+
+    /// Counts the items waiting in the delivery queue, and lists up to `limit` of them as event and
+    ///  data, oldest first, without taking them.
+    pub async fn queue_snapshot(
+        &self,
+        limit: u32,
+    ) -> Result<(u64, Vec<(String, String)>), StoreError> {
+        let (depth, items) = match &self.0 {
+            Backend::PostgreSQL(_, stats) => stats.snapshot(limit).await?,
+            Backend::Memory(memory) => (memory.queue_depth(), memory.queue_items(limit)),
+            Backend::Sqlite(pool) => {
+                let store = SqliteStore::new(pool.get().await?);
+                (store.queue_depth().await?, store.queue_items(limit).await?)
+            }
+        };
+
+        let items = items
+            .into_iter()
+            .map(|(event, data)| (event, logging::split_request_id(&data).1.to_owned()))
+            .collect();
+
+        Ok((depth, items))
+    }
+}
+
+impl StorePool for DatabasePool {
+    type LeasedConnection = DatabaseConnection;
+
+    fn connect(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::LeasedConnection, StoreError>> + Send + 'static>>
+    {
+        let backend = self.0.clone();
+        let shutdown = self.1.clone();
+        let metrics = self.2.clone();
+
+        Box::pin(async move {
+            let start = Instant::now();
+
+            let connection = match backend {
+                Backend::PostgreSQL(pool, _) => {
+                    let conn = Arc::new(pool.get().await?);
+
+                    DatabaseConnection::PostgreSQL(
+                        MeteredStore::new(
+                            TracedStore::new(CellarStore(conn.clone())),
+                            metrics.clone(),
+                        ),
+                        ShutdownQueue::new(
+                            MeteredStore::new(TracedStore::new(CellarStore(conn)), metrics.clone()),
+                            shutdown,
+                        ),
+                    )
+                }
+
+                Backend::Memory(memory) => DatabaseConnection::Memory(
+                    MeteredStore::new(TracedStore::new(memory.clone()), metrics.clone()),
+                    ShutdownQueue::new(
+                        MeteredStore::new(TracedStore::new(memory), metrics.clone()),
+                        shutdown,
+                    ),
+                ),
+
+                Backend::Sqlite(pool) => {
+                    let store = SqliteStore::new(pool.get().await?);
+
+                    DatabaseConnection::Sqlite(
+                        MeteredStore::new(TracedStore::new(store.clone()), metrics.clone()),
+                        ShutdownQueue::new(
+                            MeteredStore::new(TracedStore::new(store), metrics.clone()),
+                            shutdown,
+                        ),
+                    )
+                }
+            };
+
+            metrics.lease(start.elapsed());
+
+            Ok(connection)
+        })
+    }
+}
+
+// These only run safe code, but the drop order they cover is what the old raw-pointer version got
+//  wrong, so they are worth running under Miri too: `cargo miri test database::tests`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PoolConfig;
+    use futures::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts the connections that are alive, so a leaked or doubly dropped one shows up.
+    struct Tracked(Arc<AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    struct TrackingManager(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl Manager for TrackingManager {
+        type Connection = Tracked;
+
+        async fn connect(&self) -> Result<Tracked, StoreError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Tracked(self.0.clone()))
+        }
+
+        async fn check(&self, _: &mut Tracked) -> Result<(), StoreError> {
+            Ok(())
+        }
+    }
+
+    fn pool(live: &Arc<AtomicUsize>) -> Pool<TrackingManager> {
+        Pool::new(
+            TrackingManager(live.clone()),
+            PoolConfig {
+                max_connections: 1,
+                min_idle: 0,
+                idle_timeout: 600,
+                acquire_timeout: 1,
+            },
+        )
+    }
+
+    // Shares one lease between an entity and a queue handle, the way `connect` builds a
+    //  `DatabaseConnection::PostgreSQL`.
+    fn lease(
+        pool: &Pool<TrackingManager>,
+    ) -> (Arc<Pooled<TrackingManager>>, Arc<Pooled<TrackingManager>>) {
+        let connection = Arc::new(block_on(pool.get()).unwrap());
+
+        (connection.clone(), connection)
+    }
+
+    #[test]
+    fn connection_returns_once_both_handles_drop() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let pool = pool(&live);
+
+        for &entity_first in &[true, false] {
+            let (entity, queue) = lease(&pool);
+
+            if entity_first {
+                drop(entity);
+                assert!(block_on(async_std::future::timeout(
+                    Duration::from_millis(50),
+                    pool.get()
+                ))
+                .is_err());
+                drop(queue);
+            } else {
+                drop(queue);
+                drop(entity);
+            }
+
+            // The connection is idle again and reused, rather than a second one being opened.
+            drop(lease(&pool));
+            assert_eq!(live.load(Ordering::SeqCst), 1);
+        }
+
+        drop(pool);
+        assert_eq!(live.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn lease_can_outlive_the_pool() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let pool = pool(&live);
+        let (entity, queue) = lease(&pool);
+
+        drop(pool);
+        drop(queue);
+        assert_eq!(live.load(Ordering::SeqCst), 1);
+
+        drop(entity);
+        assert_eq!(live.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn snapshots_the_queue_without_taking_items() {
+        let pool = DatabasePool::new(DatabaseConfig::Memory);
+
+        block_on(async {
+            let mut conn = pool.connect().await.unwrap();
+            let (_, queue) = conn.get();
+
+            // The request ID the items carry isn't part of their data.
+            logging::scope(Some("request".to_owned()), async {
+                for index in 0..3 {
+                    queue
+                        .add("deliver".to_owned(), index.to_string())
+                        .await
+                        .unwrap();
+                }
+            })
+            .await;
+
+            let (depth, items) = pool.queue_snapshot(2).await.unwrap();
+            assert_eq!(depth, 3);
+            assert_eq!(
+                items,
+                vec![
+                    ("deliver".to_owned(), "0".to_owned()),
+                    ("deliver".to_owned(), "1".to_owned()),
+                ]
+            );
+
+            assert_eq!(pool.queue_depth().await.unwrap(), 3);
+        });
+    }
+}
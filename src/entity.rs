@@ -1,9 +1,10 @@
 use crate::config;
+use crate::database::DatabasePool;
 use clap::ArgMatches;
 use kroeg_server::{
     config::ServerConfig, context, store::RetrievingEntityStore, LeasedConnection, StorePool,
 };
-use kroeg_tap::{EntityStore, StoreItem};
+use kroeg_tap::{EntityStore, StoreError, StoreItem};
 use serde_json::Value;
 use std::io::{stdin, BufRead};
 
@@ -32,8 +33,8 @@ async fn get(
     }
 }
 
-async fn set(config: &ServerConfig, store: &mut dyn EntityStore, id: String, format: &str) {
-    let data = serde_json::from_reader(stdin()).unwrap();
+/// Expands a JSON-LD document into the store item it describes.
+pub async fn parse_document(id: &str, data: Value) -> Result<StoreItem, StoreError> {
     let expanded = jsonld::expand::<context::SurfContextLoader>(
         &context::apply_supplement(data),
         &jsonld::JsonLdOptions {
@@ -44,11 +45,27 @@ async fn set(config: &ServerConfig, store: &mut dyn EntityStore, id: String, for
         },
     )
     .await
-    .expect("Failed to expand");
+    .map_err(|e| format!("failed to expand: {:?}", e))?;
 
-    let mut item = StoreItem::parse(&id, &expanded).expect("Failed to parse as store item");
-    store
-        .put(id, &mut item)
+    Ok(StoreItem::parse(id, &expanded)
+        .map_err(|e| format!("failed to parse as store item: {:?}", e))?)
+}
+
+/// Expands a JSON-LD document and stores it as the entity `id`.
+pub async fn put_document(
+    store: &mut dyn EntityStore,
+    id: String,
+    data: Value,
+) -> Result<StoreItem, StoreError> {
+    let mut item = parse_document(&id, data).await?;
+    store.put(id, &mut item).await?;
+
+    Ok(item)
+}
+
+async fn set(config: &ServerConfig, store: &mut dyn EntityStore, id: String, format: &str) {
+    let data = serde_json::from_reader(stdin()).unwrap();
+    let item = put_document(store, id, data)
         .await
         .expect("failed to put entity");
 
@@ -84,7 +101,7 @@ pub async fn handle(config: config::KroegConfig, matches: &ArgMatches<'_>) {
     let is_remote = matches.is_present("remote");
     let format = matches.value_of("format").unwrap();
     let id = matches.value_of("ID").unwrap();
-    let pool = crate::DatabasePool(config.database);
+    let pool = DatabasePool::new(config.database);
     let mut conn = pool.connect().await.expect("Database connection failed");
 
     let mut entitystore = RetrievingEntityStore::new(conn.get().0, config.server.domain.to_owned());
@@ -123,7 +140,7 @@ pub async fn handle(config: config::KroegConfig, matches: &ArgMatches<'_>) {
 }
 
 pub async fn handle_query(config: config::KroegConfig) {
-    let pool = crate::DatabasePool(config.database);
+    let pool = DatabasePool::new(config.database);
     let mut conn = pool.connect().await.expect("Database connection failed");
 
     let (store, _) = conn.get();
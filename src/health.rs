@@ -0,0 +1,111 @@
+use crate::database::DatabasePool;
+use crate::supervisor::WorkerStatus;
+use http::Response;
+use http_service::Body;
+use kroeg_server::{router::RequestHandler, LeasedConnection, ServerError, StorePool};
+use kroeg_tap::Context;
+use serde_json::{json, Map, Value};
+use std::time::{Duration, Instant};
+
+// Probes give up long before the pool's acquire timeout would, so a probe that can't get a
+//  connection fails quickly instead of holding on to the prober.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn respond(checks: Map<String, Value>) -> http_service::Response {
+    let healthy = checks.values().all(|check| check["status"] == "ok");
+    let body = json!({
+        "status": if healthy { "ok" } else { "error" },
+        "checks": checks,
+    });
+
+    Response::builder()
+        .status(if healthy { 200 } else { 503 })
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn check<T, E: std::fmt::Display>(start: Instant, result: &Result<T, E>) -> Value {
+    let latency = start.elapsed().as_secs_f64() * 1000.0;
+
+    match result {
+        Ok(_) => json!({ "status": "ok", "latency_ms": latency }),
+        Err(e) => json!({ "status": "error", "latency_ms": latency, "error": e.to_string() }),
+    }
+}
+
+pub const HEALTH_PATH: &str = "/-/health";
+pub const READY_PATH: &str = "/-/ready";
+
+/// The `/-/health` response: healthy as long as the process is able to serve requests at all, and
+///  the delivery workers it should run are running. This never touches the database.
+pub fn health(workers: &WorkerStatus) -> http_service::Response {
+    let mut checks = Map::new();
+    checks.insert(
+        "process".to_owned(),
+        json!({ "status": "ok", "latency_ms": 0.0 }),
+    );
+
+    if workers.total() > 0 {
+        let status = if workers.live() > 0 { "ok" } else { "error" };
+        checks.insert(
+            "workers".to_owned(),
+            json!({ "status": status, "live": workers.live(), "total": workers.total() }),
+        );
+    }
+
+    respond(checks)
+}
+
+/// `/-/health` as a route, so it shows up in `routes`. `serve` answers it before a request gets
+///  this far, see `FrontService`.
+pub struct HealthHandler(pub WorkerStatus);
+
+#[async_trait::async_trait]
+impl RequestHandler for HealthHandler {
+    async fn run(
+        &self,
+        _: &mut Context<'_, '_>,
+        _: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        Ok(health(&self.0))
+    }
+}
+
+/// The `/-/ready` response: only ready if a database connection can be leased and used. This takes
+///  a single lease, and fails if that doesn't come within `READY_TIMEOUT`.
+pub async fn ready(pool: DatabasePool) -> http_service::Response {
+    let mut checks = Map::new();
+
+    let start = Instant::now();
+    let lease = match async_std::future::timeout(READY_TIMEOUT, pool.connect()).await {
+        Ok(lease) => lease,
+        Err(_) => Err("timed out waiting for a database connection".into()),
+    };
+    checks.insert("database_lease".to_owned(), check(start, &lease));
+
+    if let Ok(mut conn) = lease {
+        let (store, _) = conn.get();
+
+        let start = Instant::now();
+        let query = store.get("kroeg:health-check".to_owned(), true).await;
+        checks.insert("database_query".to_owned(), check(start, &query));
+    }
+
+    respond(checks)
+}
+
+/// `/-/ready` as a route, like `HealthHandler`.
+pub struct ReadyHandler(pub DatabasePool);
+
+#[async_trait::async_trait]
+impl RequestHandler for ReadyHandler {
+    async fn run(
+        &self,
+        _: &mut Context<'_, '_>,
+        _: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        Ok(ready(self.0.clone()).await)
+    }
+}
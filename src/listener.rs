@@ -0,0 +1,392 @@
+use crate::shutdown::Shutdown;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::pin_mut;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+// The first file descriptor passed in by systemd, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where `serve` should accept connections: `unix:/path/to.sock`, `systemd` for a socket passed in
+///  through socket activation, or a TCP address.
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Systemd,
+}
+
+impl ListenAddress {
+    pub fn parse(address: &str) -> Result<ListenAddress, String> {
+        if address == "systemd" {
+            Ok(ListenAddress::Systemd)
+        } else if address.starts_with("unix:") {
+            Ok(ListenAddress::Unix(PathBuf::from(
+                &address["unix:".len()..],
+            )))
+        } else {
+            address
+                .parse()
+                .map(ListenAddress::Tcp)
+                .map_err(|_| format!("invalid listen address: {}", address))
+        }
+    }
+
+    /// Whether systemd passed us a socket meant for this process.
+    pub fn systemd_available() -> bool {
+        let pid_matches = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            == Some(std::process::id());
+        let fds = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|fds| fds.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        pid_matches && fds > 0
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddress::Systemd => write!(f, "systemd socket"),
+        }
+    }
+}
+
+fn is_unix_socket(fd: RawFd) -> io::Result<bool> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    let result =
+        unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(addr.ss_family as libc::c_int == libc::AF_UNIX)
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+
+    /// A socket this process created is removed again when the listener is dropped, as long as
+    ///  the path still refers to it.
+    Unix(UnixListener, Option<(PathBuf, u64)>),
+}
+
+impl Listener {
+    /// Binds to the address. New Unix sockets get `mode` as their permissions, if set.
+    pub async fn bind(address: &ListenAddress, mode: Option<u32>) -> io::Result<Listener> {
+        match address {
+            ListenAddress::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+
+            ListenAddress::Unix(path) => {
+                // A socket left behind by a previous run would make the bind fail, but a socket
+                //  something still answers on, or anything else at that path, is not ours to remove.
+                match fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => {
+                        match std::os::unix::net::UnixStream::connect(path) {
+                            Ok(_) => {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::AddrInUse,
+                                    format!("{} is in use by another process", path.display()),
+                                ))
+                            }
+                            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                                fs::remove_file(path)?
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} exists and is not a socket", path.display()),
+                        ))
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+
+                let listener = UnixListener::from(std::os::unix::net::UnixListener::bind(path)?);
+                let inode = fs::symlink_metadata(path)?.ino();
+                let listener = Listener::Unix(listener, Some((path.clone(), inode)));
+
+                // Setting the umask around the bind would change it for every thread, so the
+                //  permissions are set afterwards. If that fails, dropping the listener removes
+                //  the socket again.
+                if let Some(mode) = mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                }
+
+                Ok(listener)
+            }
+
+            ListenAddress::Systemd => {
+                if !ListenAddress::systemd_available() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "no socket was passed in by systemd",
+                    ));
+                }
+
+                std::env::remove_var("LISTEN_PID");
+                std::env::remove_var("LISTEN_FDS");
+                std::env::remove_var("LISTEN_FDNAMES");
+
+                let fd = SD_LISTEN_FDS_START;
+                if is_unix_socket(fd)? {
+                    Ok(Listener::Unix(
+                        unsafe { UnixListener::from_raw_fd(fd) },
+                        None,
+                    ))
+                } else {
+                    Ok(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }))
+                }
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => Ok(Connection::Tcp(listener.accept().await?.0)),
+            Listener::Unix(listener, _) => Ok(Connection::Unix(listener.accept().await?.0)),
+        }
+    }
+
+    /// Like `accept`, but returns `None` once the shutdown is triggered. The caller should drop
+    ///  the listener then, which closes it so new connections are refused instead of queued.
+    pub async fn accept_until(&self, shutdown: &Shutdown) -> Option<io::Result<Connection>> {
+        let accept = self.accept();
+        let triggered = shutdown.triggered();
+        pin_mut!(accept, triggered);
+
+        match future::select(accept, triggered).await {
+            Either::Left((result, _)) => Some(result),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some((path, inode))) = self {
+            // Another process may have replaced the socket since, which has to stay.
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.ino() == *inode => {
+                    let _ = fs::remove_file(&path);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Waits after a failed accept. These mostly happen when the process is out of file descriptors,
+///  and retrying right away would only spin until some are freed. The delay doubles while accepting
+///  keeps failing.
+#[derive(Default)]
+pub struct AcceptBackoff(Option<Duration>);
+
+impl AcceptBackoff {
+    pub fn reset(&mut self) {
+        self.0 = None;
+    }
+
+    pub async fn wait(&mut self, error: io::Error) {
+        let delay = self.0.map_or(MIN_ACCEPT_BACKOFF, |delay| {
+            (delay * 2).min(MAX_ACCEPT_BACKOFF)
+        });
+        self.0 = Some(delay);
+
+        log::warn!(
+            "Failed to accept connection, retrying in {:?}: {}",
+            delay,
+            error
+        );
+        async_std::task::sleep(delay).await;
+    }
+}
+
+/// An accepted connection on either kind of listener.
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    /// The address of the other end, for TCP connections.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().ok(),
+            Connection::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_close(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+/// Accepts connections on a separate task, yielding them as a stream for the HTTP server. The
+///  listener is closed once the shutdown is triggered.
+pub fn incoming(listener: Listener, shutdown: Shutdown) -> UnboundedReceiver<Connection> {
+    let (sender, receiver) = unbounded();
+
+    async_std::task::spawn(async move {
+        let mut backoff = AcceptBackoff::default();
+
+        while let Some(result) = listener.accept_until(&shutdown).await {
+            match result {
+                Ok(connection) => {
+                    backoff.reset();
+                    if sender.unbounded_send(connection).is_err() {
+                        return;
+                    }
+                }
+
+                Err(e) => backoff.wait(e).await,
+            }
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("kroeg-{}-{}.sock", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn binds_with_mode_and_cleans_up() {
+        let path = socket_path("listener-mode");
+        let address = ListenAddress::Unix(path.clone());
+
+        let listener = block_on(Listener::bind(&address, Some(0o600))).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.mode() & 0o777, 0o600);
+
+        drop(listener);
+        assert!(fs::symlink_metadata(&path).is_err());
+    }
+
+    #[test]
+    fn replaces_stale_sockets() {
+        let path = socket_path("listener-stale");
+        let address = ListenAddress::Unix(path.clone());
+
+        // Closing a listener leaves its socket file behind, with nothing answering on it.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+
+        let listener = block_on(Listener::bind(&address, None)).unwrap();
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+
+        drop(listener);
+    }
+
+    #[test]
+    fn refuses_sockets_in_use() {
+        let path = socket_path("listener-in-use");
+        let address = ListenAddress::Unix(path.clone());
+
+        let other = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let error = block_on(Listener::bind(&address, None)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+
+        drop(other);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_remove_other_files() {
+        let path = socket_path("listener-file");
+        fs::write(&path, "data").unwrap();
+
+        let address = ListenAddress::Unix(path.clone());
+        assert!(block_on(Listener::bind(&address, None)).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn leaves_replaced_sockets_alone() {
+        let path = socket_path("listener-replaced");
+        let address = ListenAddress::Unix(path.clone());
+
+        let listener = block_on(Listener::bind(&address, None)).unwrap();
+        fs::remove_file(&path).unwrap();
+        let other = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        drop(listener);
+        assert!(fs::symlink_metadata(&path).is_ok());
+
+        drop(other);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,467 @@
+use crate::metrics::delivery_host;
+use http::header::HeaderValue;
+use kroeg_server::{router::RequestHandler, ServerError};
+use kroeg_tap::{
+    CollectionPointer, Context, EntityStore, QuadQuery, QueueItem, QueueStore, StoreError,
+    StoreItem,
+};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Instant;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// Queued items carry the ID of the request that queued them in front of their data, so whichever
+//  worker process takes them can log under it.
+const QUEUED_REQUEST_ID: &str = "kroeg-request-id:";
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Format, String> {
+        match format {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {:?}", format)),
+        }
+    }
+}
+
+struct Logger {
+    format: Format,
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let request_id = request_id();
+
+        let line = match self.format {
+            Format::Human => format!(
+                "{} {:<5} {} {}{}",
+                timestamp,
+                record.level(),
+                record.target(),
+                request_id
+                    .map(|id| format!("[{}] ", id))
+                    .unwrap_or_default(),
+                record.args()
+            ),
+
+            Format::Json => json!({
+                "timestamp": timestamp,
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+                "request_id": request_id,
+            })
+            .to_string(),
+        };
+
+        let stderr = std::io::stderr();
+        let _ = writeln!(stderr.lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Installs the logger for the whole process. Everything is written to stderr, so the output of
+///  commands like `entity get` stays clean.
+pub fn init(format: Format, level: LevelFilter) {
+    log::set_boxed_logger(Box::new(Logger { format, level }))
+        .map(|()| log::set_max_level(level))
+        .expect("Failed to install logger");
+}
+
+/// The ID of the request or delivery the current task is working on, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+/// Changes the ID for the rest of the surrounding `scope`.
+pub fn set_request_id(id: Option<String>) {
+    REQUEST_ID.with(|current| *current.borrow_mut() = id);
+}
+
+pub fn new_request_id() -> String {
+    let mut bytes = [0u8; 8];
+    openssl::rand::rand_bytes(&mut bytes).expect("Failed to generate request ID");
+
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Puts the current request ID in front of the data of an item that is being queued.
+fn with_request_id(data: String) -> String {
+    match request_id() {
+        Some(id) => format!("{}{}\n{}", QUEUED_REQUEST_ID, id, data),
+        None => data,
+    }
+}
+
+/// Splits the request ID off the data of a queued item, if it has one.
+pub fn split_request_id(data: &str) -> (Option<&str>, &str) {
+    if data.starts_with(QUEUED_REQUEST_ID) {
+        let rest = &data[QUEUED_REQUEST_ID.len()..];
+        if let Some(end) = rest.find('\n') {
+            if valid_request_id(&rest[..end]) {
+                return (Some(&rest[..end]), &rest[end + 1..]);
+            }
+        }
+    }
+
+    (None, data)
+}
+
+// Items are handed back within the delivery's scope, so its request ID goes back in front of the
+//  data, and a retry keeps it.
+fn queued(item: Box<dyn QueueItem + Send>) -> Box<dyn QueueItem + Send> {
+    Box::new(TracedItem {
+        event: item.event().to_owned(),
+        data: with_request_id(item.data().to_owned()),
+    })
+}
+
+/// A queue item with its data changed, so the rest of the server doesn't see the request ID in it
+///  and the store gets it back.
+struct TracedItem {
+    event: String,
+    data: String,
+}
+
+impl QueueItem for TracedItem {
+    fn event(&self) -> &str {
+        &self.event
+    }
+
+    fn data(&self) -> &str {
+        &self.data
+    }
+}
+
+/// Wraps an entity and queue store, logging how long each entity store operation took and what
+///  happens to each delivery. Deliveries are logged under the ID of the request that queued them,
+///  items queued without one get a new ID when they are taken.
+pub struct TracedStore<S>(S);
+
+impl<S> TracedStore<S> {
+    pub fn new(inner: S) -> TracedStore<S> {
+        TracedStore(inner)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: EntityStore> EntityStore for TracedStore<S> {
+    async fn get(&mut self, path: String, local: bool) -> Result<Option<StoreItem>, StoreError> {
+        let start = Instant::now();
+        let result = self.0.get(path, local).await;
+        log::trace!("get took {:?}", start.elapsed());
+
+        result
+    }
+
+    async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.0.put(path, item).await;
+        log::trace!("put took {:?}", start.elapsed());
+
+        result
+    }
+
+    async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
+        let start = Instant::now();
+        let result = self.0.query(query).await;
+        log::trace!("query took {:?}", start.elapsed());
+
+        result
+    }
+
+    async fn read_collection(
+        &mut self,
+        path: String,
+        count: Option<u32>,
+        cursor: Option<String>,
+    ) -> Result<CollectionPointer, StoreError> {
+        let start = Instant::now();
+        let result = self.0.read_collection(path, count, cursor).await;
+        log::trace!("read_collection took {:?}", start.elapsed());
+
+        result
+    }
+
+    async fn find_collection(
+        &mut self,
+        path: String,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let start = Instant::now();
+        let result = self.0.find_collection(path, item).await;
+        log::trace!("find_collection took {:?}", start.elapsed());
+
+        result
+    }
+
+    async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.0.insert_collection(path, item).await;
+        log::trace!("insert_collection took {:?}", start.elapsed());
+
+        result
+    }
+
+    async fn read_collection_inverse(
+        &mut self,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let start = Instant::now();
+        let result = self.0.read_collection_inverse(item).await;
+        log::trace!("read_collection_inverse took {:?}", start.elapsed());
+
+        result
+    }
+
+    async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.0.remove_collection(path, item).await;
+        log::trace!("remove_collection took {:?}", start.elapsed());
+
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: QueueStore> QueueStore for TracedStore<S> {
+    async fn get_item(&mut self) -> Result<Option<Box<dyn QueueItem + Send>>, StoreError> {
+        let item = match self.0.get_item().await? {
+            Some(item) => item,
+            None => {
+                set_request_id(None);
+                return Ok(None);
+            }
+        };
+
+        let (id, data) = split_request_id(item.data());
+        set_request_id(Some(id.map(str::to_owned).unwrap_or_else(new_request_id)));
+
+        let item = TracedItem {
+            event: item.event().to_owned(),
+            data: data.to_owned(),
+        };
+        log::debug!(
+            "Processing {} for {}",
+            item.event,
+            delivery_host(&item.data)
+        );
+
+        Ok(Some(Box::new(item)))
+    }
+
+    async fn mark_success(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        log::info!(
+            "Finished {} for {}",
+            item.event(),
+            delivery_host(item.data())
+        );
+        self.0.mark_success(queued(item)).await
+    }
+
+    async fn mark_failure(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        log::warn!(
+            "Failed {} for {}, retrying later",
+            item.event(),
+            delivery_host(item.data())
+        );
+        self.0.mark_failure(queued(item)).await
+    }
+
+    async fn add(&mut self, event: String, data: String) -> Result<(), StoreError> {
+        log::debug!("Queued {} for {}", event, delivery_host(&data));
+        self.0.add(event, with_request_id(data)).await
+    }
+}
+
+struct Restore(Option<String>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let outer = self.0.take();
+        REQUEST_ID.with(|current| *current.borrow_mut() = outer);
+    }
+}
+
+/// A future that has its own request ID, visible to everything it runs while being polled.
+pub struct Scoped<F> {
+    id: Option<String>,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<F::Output> {
+        let this = self.get_mut();
+
+        let outer = REQUEST_ID.with(|current| current.replace(this.id.take()));
+        let restore = Restore(outer);
+
+        let result = this.inner.as_mut().poll(cx);
+
+        this.id = request_id();
+        drop(restore);
+
+        result
+    }
+}
+
+pub fn scope<F: Future>(id: Option<String>, future: F) -> Scoped<F> {
+    Scoped {
+        id,
+        inner: Box::pin(future),
+    }
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Wraps a route's handler, giving every request an ID that shows up in all logs written while
+///  handling it. An `X-Request-Id` from a proxy in front is reused, and the ID is sent back in the
+///  response.
+pub struct TracingHandler(Box<dyn RequestHandler>);
+
+impl TracingHandler {
+    pub fn new(inner: Box<dyn RequestHandler>) -> TracingHandler {
+        TracingHandler(inner)
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for TracingHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| valid_request_id(id))
+            .map(str::to_owned)
+            .unwrap_or_else(new_request_id);
+
+        let method = request.method().clone();
+        let path = request.uri().path().to_owned();
+
+        let result = scope(Some(id.clone()), async move {
+            log::debug!("Handling {} {}", method, path);
+
+            let result = self.0.run(context, request).await;
+            if let Err(e) = &result {
+                log::warn!("Request failed: {:?}", e);
+            }
+
+            result
+        })
+        .await;
+
+        result.map(|mut response| {
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use async_std::task::block_on;
+
+    #[test]
+    fn request_ids_are_split_off_queued_data() {
+        assert_eq!(
+            split_request_id("kroeg-request-id:abc\nhttps://example.com/inbox"),
+            (Some("abc"), "https://example.com/inbox")
+        );
+        assert_eq!(
+            split_request_id("https://example.com/inbox"),
+            (None, "https://example.com/inbox")
+        );
+        assert_eq!(
+            split_request_id("kroeg-request-id:not valid\ndata"),
+            (None, "kroeg-request-id:not valid\ndata")
+        );
+    }
+
+    #[test]
+    fn deliveries_keep_their_request_id() {
+        let memory = MemoryStore::new();
+
+        // Items are taken by another store, the way a worker in another process would.
+        let mut web = TracedStore::new(memory.clone());
+        let mut worker = TracedStore::new(memory);
+
+        block_on(scope(Some("origin".to_owned()), async {
+            web.add("deliver".to_owned(), "traced-delivery".to_owned())
+                .await
+                .unwrap();
+        }));
+
+        block_on(scope(None, async {
+            web.add("deliver".to_owned(), "untraced-delivery".to_owned())
+                .await
+                .unwrap();
+
+            let item = worker.get_item().await.unwrap().unwrap();
+            assert_eq!(item.data(), "traced-delivery");
+            assert_eq!(request_id(), Some("origin".to_owned()));
+            worker.mark_failure(item).await.unwrap();
+
+            let item = worker.get_item().await.unwrap().unwrap();
+            assert_eq!(item.data(), "untraced-delivery");
+            assert_ne!(request_id(), Some("origin".to_owned()));
+            assert!(request_id().is_some());
+
+            // A retry is still logged under the request that queued it.
+            let item = worker.get_item().await.unwrap().unwrap();
+            assert_eq!(item.data(), "traced-delivery");
+            assert_eq!(request_id(), Some("origin".to_owned()));
+
+            assert!(worker.get_item().await.unwrap().is_none());
+            assert_eq!(request_id(), None);
+        }));
+    }
+}
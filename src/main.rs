@@ -1,144 +1,209 @@
+use access_log::{AccessLog, AccessLogHandler};
 use clap::{App, AppSettings, Arg, SubCommand};
-use http::Response;
-use http_service::Body;
-use kroeg_cellar::{CellarConnection, CellarEntityStore};
-use kroeg_server::{
-    context, get, launch_delivery, nodeinfo, post, router::RequestHandler, router::Route,
-    webfinger, KroegService, LeasedConnection, ServerError, StorePool,
-};
-use kroeg_tap::{Context, EntityStore, QueueStore, StoreError};
-use std::fs::File;
+use compression::CompressionHandler;
+use database::DatabasePool;
+use futures::future;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::{self, Stream, StreamExt};
+use http_service::HttpService;
+use kroeg_server::{router::RequestHandler, router::Route, KroegService};
+use listener::{ListenAddress, Listener};
+use logging::TracingHandler;
+use metrics::{MeteredHandler, Metrics, MetricsHandler};
+use rate_limit::RateLimitHandler;
+use service::{FrontService, TaskSpawner};
+use shutdown::{DrainingHandler, Shutdown};
+use std::fmt::Display;
 use std::future::Future;
-use std::io::Read;
-use std::pin::Pin;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use supervisor::WorkerStatus;
 
+mod access_log;
+mod admin;
+mod caching;
+mod compression;
 mod config;
+mod configure;
+mod cors;
+mod database;
 mod entity;
+mod health;
+mod listener;
+mod logging;
+mod memory;
+mod metrics;
+mod pool;
+mod query;
+mod rate_limit;
 mod request;
+mod routes;
+mod schema;
+mod service;
+mod shutdown;
+mod sqlite;
+mod supervisor;
+mod tls;
 mod user;
 
-struct ContextHandler;
-
-#[async_trait::async_trait]
-impl RequestHandler for ContextHandler {
-    async fn run(
-        &self,
-        _: &mut Context<'_, '_>,
-        _: http_service::Request,
-    ) -> Result<http_service::Response, ServerError> {
-        Ok(Response::builder()
-            .status(200)
-            .header("Content-Type", "application/ld+json")
-            .body(Body::from(context::read_context().to_string()))
-            .unwrap())
+fn listen(
+    address: &ListenAddress,
+    config: &config::KroegConfig,
+    pool: DatabasePool,
+    workers: &WorkerStatus,
+    metrics: &Metrics,
+    shutdown: &Shutdown,
+) {
+    let compression = config.http.compression.clone().unwrap_or_default();
+    let routes = routes::table(config, &pool, workers, metrics);
+
+    if let Err(e) = rate_limit::check_routes(&config.http.rate_limit, &routes) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
     }
-}
 
-fn listen(address: &str, config: &config::KroegConfig) {
-    let addr = address
-        .parse()
-        .expect(format!("Invalid listen address! {address}").as_str());
-
-    let mut routes = vec![
-        Route::get_prefix("/", get::GetHandler),
-        Route::post_prefix("/", post::PostHandler),
-    ];
-
-    #[cfg(feature = "frontend")]
-    routes.append(&mut kroeg_frontend::routes().expect("Failed to register frontend"));
-
-    // Ensure GETs with the proper Accept get handled ActivityPub-first.
-    routes.push(Route {
-        content_type: vec![
-            "application/activity+json".to_string(),
-            "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"".to_string(),
-        ],
-        method: http::Method::GET,
-        path: String::new(),
-        is_prefix: true,
-        handler: Box::new(get::GetHandler),
-    });
+    let access_log = config
+        .http
+        .access_log
+        .as_ref()
+        .map(|access_log| AccessLog::open(access_log).expect("Failed to open access log"));
 
-    routes.push(Route::get("/-/context", ContextHandler));
-    routes.append(&mut nodeinfo::routes());
-    routes.append(&mut webfinger::routes());
+    let routes = routes
+        .into_iter()
+        .map(|routes::Entry { route, .. }| {
+            let label = routes::label(&route);
+            let mut handler = route.handler;
 
-    #[cfg(feature = "mastodon")]
-    routes.append(&mut kroeg_mastodon::routes());
+            if let Some(limits) = config.http.rate_limit.get(&label) {
+                handler = Box::new(RateLimitHandler::new(
+                    handler,
+                    label.clone(),
+                    limits,
+                    &config.server.domain,
+                ));
+            }
 
-    #[cfg(feature = "oauth")]
-    routes.append(&mut kroeg_oauth::routes());
+            let handler = MeteredHandler::new(handler, label.clone(), metrics.clone());
+            let mut handler: Box<dyn RequestHandler> =
+                Box::new(DrainingHandler::new(Box::new(handler), shutdown.clone()));
 
-    let pool = DatabasePool(config.database.clone());
-    let builder = KroegService::new(pool, config.server.clone(), routes);
+            if config.http.compression.is_some() {
+                handler = Box::new(CompressionHandler::new(handler, compression.clone()));
+            }
 
-    println!("Listening at: {}", addr);
-    http_service_hyper::run(builder, addr);
-}
+            if access_log.is_some() {
+                handler = Box::new(AccessLogHandler::new(handler, label));
+            }
 
-// Using a raw pointer here to 100% ensure the connection outlives the EntityStore and QueueStore
-//  that make use of it. It is an ugly hack.
-enum DatabaseConnection {
-    PostgreSQL(
-        *mut CellarConnection,
-        Option<(CellarEntityStore<'static>, CellarEntityStore<'static>)>,
-    ),
-}
+            Route {
+                handler: Box::new(TracingHandler::new(handler)),
+                ..route
+            }
+        })
+        .collect();
 
-unsafe impl Send for DatabaseConnection {}
+    let mut service = FrontService::new(
+        KroegService::new(pool.clone(), config.server.clone(), routes),
+        pool,
+        workers.clone(),
+        config.http.trusted_proxies.clone(),
+    );
 
-impl LeasedConnection for DatabaseConnection {
-    fn get(&mut self) -> (&mut dyn EntityStore, &mut dyn QueueStore) {
-        match self {
-            DatabaseConnection::PostgreSQL(_, Some((left, right))) => (left, right),
+    if let Some(access_log) = access_log {
+        service = service.with_access_log(access_log);
+    }
 
-            _ => unreachable!(),
+    let socket = async_std::task::block_on(Listener::bind(address, config.http.socket_mode))
+        .unwrap_or_else(|e| fail(format!("failed to listen on {}: {}", address, e)));
+
+    match &config.http.tls {
+        Some(tls) => {
+            let incoming = tls::incoming(socket, tls.clone(), shutdown.clone())
+                .unwrap_or_else(|e| fail(format!("failed to set up TLS: {}", e)));
+
+            log::info!("Listening at: {} (TLS)", address);
+            serve_connections(incoming, service);
         }
-    }
-}
 
-impl Drop for DatabaseConnection {
-    fn drop(&mut self) {
-        match self {
-            DatabaseConnection::PostgreSQL(conn, data) => {
-                *data = None;
-                unsafe { Box::from_raw(*conn) };
-            }
+        None => {
+            let incoming = listener::incoming(socket, shutdown.clone())
+                .map(|connection| (connection.peer_addr(), connection));
+
+            log::info!("Listening at: {}", address);
+            serve_connections(incoming, service);
         }
     }
 }
 
-struct DatabasePool(config::DatabaseConfig);
+/// Serves each connection with a copy of the service that knows the connection's peer address,
+///  which the HTTP server wouldn't pass on otherwise.
+fn serve_connections<S, C>(
+    mut incoming: impl Stream<Item = (Option<SocketAddr>, C)> + Unpin + Send + 'static,
+    service: FrontService<S>,
+) where
+    S: HttpService,
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    FrontService<S>: HttpService,
+{
+    async_std::task::spawn(async move {
+        while let Some((peer, connection)) = incoming.next().await {
+            let connection = stream::once(future::ok::<_, io::Error>(connection));
 
-impl StorePool for DatabasePool {
-    type LeasedConnection = DatabaseConnection;
+            let server = http_service_hyper::Server::builder(connection)
+                .with_spawner(TaskSpawner)
+                .serve(service.for_peer(peer));
 
-    fn connect(
-        &self,
-    ) -> Pin<Box<dyn Future<Output = Result<Self::LeasedConnection, StoreError>> + Send + 'static>>
-    {
-        let cloned = self.0.clone();
-
-        Box::pin(async move {
-            match &cloned {
-                config::DatabaseConfig::PostgreSQL {
-                    server,
-                    username,
-                    password,
-                    database,
-                } => {
-                    let connection =
-                        CellarConnection::connect(server, username, password, database);
-                    let conn = Box::into_raw(Box::new(connection.await?));
-
-                    let left = CellarEntityStore::new(unsafe { &*conn });
-                    let right = CellarEntityStore::new(unsafe { &*conn });
-
-                    Ok(DatabaseConnection::PostgreSQL(conn, Some((left, right))))
+            // Unlike `run_server`, a failure only affects this one connection.
+            async_std::task::spawn(async move {
+                if let Err(e) = server.await {
+                    log::debug!("Failed to serve connection: {}", e);
                 }
-            }
-        })
-    }
+            });
+        }
+    });
+}
+
+/// Serves only `/-/metrics`, so it can be kept off the public address.
+fn listen_metrics(
+    address: &ListenAddress,
+    config: &config::KroegConfig,
+    pool: DatabasePool,
+    workers: &WorkerStatus,
+    metrics: &Metrics,
+    shutdown: &Shutdown,
+) {
+    let routes = vec![Route::get(
+        "/-/metrics",
+        MetricsHandler {
+            metrics: metrics.clone(),
+            pool: pool.clone(),
+            workers: workers.clone(),
+        },
+    )];
+
+    let builder = KroegService::new(pool, config.server.clone(), routes);
+
+    let socket = async_std::task::block_on(Listener::bind(address, config.http.socket_mode))
+        .unwrap_or_else(|e| fail(format!("failed to listen on {}: {}", address, e)));
+    let incoming = listener::incoming(socket, shutdown.clone()).map(Ok::<_, io::Error>);
+
+    log::info!("Serving metrics at: {}", address);
+    run_server(http_service_hyper::Server::builder(incoming).serve(builder));
+}
+
+fn fail(message: impl Display) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+}
+
+fn run_server<E: Display>(server: impl Future<Output = Result<(), E>> + Send + 'static) {
+    async_std::task::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("HTTP server failed: {}", e);
+            std::process::exit(1);
+        }
+    });
 }
 
 fn main() {
@@ -154,6 +219,92 @@ fn main() {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help("Overrides a config key, e.g. server.domain=example.com")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("How log lines are written to stderr")
+                .possible_values(&["human", "json"])
+                .default_value("human"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("The most detailed level of log lines to show")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .default_value("info"),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Inspects the server configuration")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Validates the config and checks that the database is reachable"),
+                )
+                .subcommand(
+                    SubCommand::with_name("init")
+                        .about(
+                            "Writes a new config file, asking for any value not passed as a flag",
+                        )
+                        .arg(
+                            Arg::with_name("domain")
+                                .help("The domain this server runs on, without trailing slash")
+                                .long("domain")
+                                .value_name("DOMAIN")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("db-url")
+                                .help("A postgres:// URL, sqlite:PATH, or memory")
+                                .long("db-url")
+                                .value_name("URL")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .help("The name this server shows up as")
+                                .long("name")
+                                .value_name("NAME")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("instance-id")
+                                .help("The instance ID, unique per database")
+                                .long("instance-id")
+                                .value_name("ID")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("force")
+                                .help("Overwrites the config file if it already exists")
+                                .long("force"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("db")
+                .about("Manages the database schema")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("init").about("Creates the schema in an empty database"),
+                )
+                .subcommand(SubCommand::with_name("migrate").about("Applies pending migrations"))
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("Shows which migrations have been applied"),
+                ),
+        )
         .subcommand(SubCommand::with_name("query").about("Runs a query passed on stdin"))
         .subcommand(
             SubCommand::with_name("entity")
@@ -239,20 +390,59 @@ fn main() {
                         .about("Sends a POST request, with the body in stdin"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("routes")
+                .about("Prints the routes `serve` mounts, in the order they are tried")
+                .arg(
+                    Arg::with_name("match")
+                        .help("Only prints the route that would handle this request")
+                        .long("match")
+                        .value_names(&["METHOD", "PATH"])
+                        .number_of_values(2),
+                )
+                .arg(
+                    Arg::with_name("accept")
+                        .help("The Accept header of the request passed to --match")
+                        .long("accept")
+                        .value_name("TYPE")
+                        .takes_value(true)
+                        .requires("match"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("serve")
-                .about("Serves an HTTP server or one or more queue workers")
+                .about("Serves an HTTP server, delivery workers, or both")
                 .arg(
                     Arg::with_name("ADDRESS")
-                        .help("The address to listen on")
+                        .help("HOST:PORT, unix:PATH, or systemd; overrides server.listen")
                         .index(1),
                 )
                 .arg(
-                    Arg::with_name("queue")
-                        .help("The amount of queue workers to spin up")
-                        .long("queue")
+                    Arg::with_name("web")
+                        .help("Runs the HTTP server; the default unless only --workers is given")
+                        .long("web"),
+                )
+                .arg(
+                    Arg::with_name("workers")
+                        .help("The amount of delivery workers to spin up")
+                        .long("workers")
+                        .alias("queue")
                         .value_name("COUNT")
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("skip-schema-check")
+                        .help("Starts even if the database has pending migrations")
+                        .long("skip-schema-check"),
+                )
+                .arg(
+                    Arg::with_name("shutdown-timeout")
+                        .help(
+                            "Seconds to let requests and deliveries finish after SIGINT or SIGTERM",
+                        )
+                        .long("shutdown-timeout")
+                        .value_name("SECONDS")
+                        .default_value("30"),
                 ),
         )
         .subcommand(
@@ -289,42 +479,150 @@ fn main() {
         )
         .get_matches();
 
-    let config_filename = matches.value_of("config").unwrap_or("server.toml");
-    let mut file = File::open(config_filename).unwrap();
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).unwrap();
-    let config: config::KroegConfig = toml::from_slice(&data).unwrap();
+    logging::init(
+        matches.value_of("log-format").unwrap().parse().unwrap(),
+        matches.value_of("log-level").unwrap().parse().unwrap(),
+    );
+
+    dotenv::dotenv().ok();
+
+    let config_filename = matches.value_of("config");
+    let overrides: Vec<&str> = matches
+        .values_of("set")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+
+    // These have to work even if the config is broken, so they load it themselves.
+    if let ("config", Some(subcommand)) = matches.subcommand() {
+        let success =
+            async_std::task::block_on(configure::handle(config_filename, &overrides, subcommand));
+
+        std::process::exit(if success { 0 } else { 1 });
+    }
+
+    let config = match config::read_file(config_filename)
+        .and_then(|data| config::KroegConfig::load(&data, std::env::vars(), &overrides))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!(
+                "error: {}: {}",
+                config_filename.unwrap_or(config::DEFAULT_FILE),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
 
     match matches.subcommand() {
         ("entity", Some(subcommand)) => {
             async_std::task::block_on(entity::handle(config, subcommand))
         }
+        ("db", Some(subcommand)) => async_std::task::block_on(schema::handle(config, subcommand)),
         ("query", _) => async_std::task::block_on(entity::handle_query(config)),
         ("request", Some(subcommand)) => {
             async_std::task::block_on(request::handle(config, subcommand))
         }
         ("actor", Some(subcommand)) => async_std::task::block_on(user::handle(config, subcommand)),
+        ("routes", Some(subcommand)) => {
+            async_std::task::block_on(routes::handle(config, subcommand))
+        }
         ("serve", Some(subcommand)) => {
-            let queue: usize = subcommand.value_of("queue").unwrap_or("0").parse().unwrap();
-            let address = subcommand
-                .value_of("ADDRESS")
-                .unwrap_or(config.server.domain.as_str());
-
-            let extra_count = if !address.is_empty() || queue == 0 {
-                queue
-            } else {
-                queue - 1
+            let workers: usize = match subcommand.value_of("workers") {
+                Some(workers) => workers
+                    .parse()
+                    .unwrap_or_else(|_| fail(format!("invalid worker count: {}", workers))),
+                None => 0,
+            };
+
+            // Without any role given, this is a plain web node.
+            let web =
+                subcommand.is_present("web") || subcommand.is_present("ADDRESS") || workers == 0;
+
+            let address = match (subcommand.value_of("ADDRESS"), &config.http.listen) {
+                (Some(address), _) => address,
+                (None, Some(address)) => address.as_str(),
+                (None, None) if ListenAddress::systemd_available() => "systemd",
+                (None, None) => config::DEFAULT_LISTEN,
             };
-            for _ in 0..extra_count {
-                let pool = DatabasePool(config.database.clone());
-                async_std::task::spawn(launch_delivery(pool, config.server.clone()));
+
+            let shutdown_timeout: u64 = subcommand
+                .value_of("shutdown-timeout")
+                .unwrap()
+                .parse()
+                .unwrap();
+
+            let shutdown = Shutdown::new();
+            shutdown.listen_for_signals();
+
+            let metrics = Metrics::new();
+
+            // All roles share one pool, so the memory backend is visible to every worker.
+            let pool = DatabasePool::new(config.database.clone())
+                .with_shutdown(shutdown.clone())
+                .with_metrics(metrics.clone());
+            if !subcommand.is_present("skip-schema-check") {
+                if let Err(e) = schema::ensure_current(&pool) {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
             }
 
-            if queue > 0 {
-                let pool = DatabasePool(config.database.clone());
-                async_std::task::block_on(launch_delivery(pool, config.server.clone()));
+            async_std::task::spawn(pool.clone().maintain());
+
+            let workers = supervisor::spawn_workers(
+                workers,
+                pool.clone(),
+                config.server.clone(),
+                shutdown.clone(),
+            );
+
+            if web {
+                let address = ListenAddress::parse(address).unwrap_or_else(fail);
+                listen(
+                    &address,
+                    &config,
+                    pool.clone(),
+                    &workers,
+                    &metrics,
+                    &shutdown,
+                );
+            }
+
+            if config.http.metrics.enabled {
+                if let Some(address) = &config.http.metrics.listen {
+                    let address = ListenAddress::parse(address).unwrap_or_else(fail);
+                    listen_metrics(
+                        &address,
+                        &config,
+                        pool.clone(),
+                        &workers,
+                        &metrics,
+                        &shutdown,
+                    );
+                }
             }
-            listen(address, &config);
+
+            async_std::task::block_on(async {
+                shutdown.triggered().await;
+
+                // Jobs are only requeued once nothing is working on them anymore, so a job that
+                //  is still running can't be delivered twice.
+                if !shutdown.drain(Duration::from_secs(shutdown_timeout)).await {
+                    log::warn!("Shutdown timed out, cancelling the remaining deliveries");
+                    shutdown.cancel_workers().await;
+                }
+
+                let unfinished = shutdown.take_unfinished();
+                if !unfinished.is_empty() {
+                    log::warn!("Requeueing {} unfinished deliveries", unfinished.len());
+                }
+
+                if let Err(e) = shutdown::requeue(&pool, unfinished).await {
+                    log::error!("Failed to requeue deliveries: {}", e);
+                    std::process::exit(1);
+                }
+            });
         }
         _ => unreachable!(),
     }
@@ -0,0 +1,373 @@
+use crate::query;
+use kroeg_tap::{
+    CollectionPointer, EntityStore, QuadQuery, QueueItem, QueueStore, StoreError, StoreItem,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct MemoryData {
+    entities: HashMap<String, StoreItem>,
+    collections: HashMap<String, Vec<(u64, String)>>,
+    collection_counter: u64,
+    queue: VecDeque<MemoryQueueItem>,
+}
+
+/// An entity and queue store that lives entirely in process memory. Every clone shares the same
+///  data, so all connections leased from one pool see each other's writes.
+#[derive(Clone, Default)]
+pub struct MemoryStore(Arc<Mutex<MemoryData>>);
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn queue_depth(&self) -> u64 {
+        self.0.lock().unwrap().queue.len() as u64
+    }
+
+    pub fn queue_items(&self, limit: u32) -> Vec<(String, String)> {
+        self.0
+            .lock()
+            .unwrap()
+            .queue
+            .iter()
+            .take(limit as usize)
+            .map(|item| (item.event.to_owned(), item.data.to_owned()))
+            .collect()
+    }
+}
+
+pub struct MemoryQueueItem {
+    event: String,
+    data: String,
+}
+
+impl QueueItem for MemoryQueueItem {
+    fn event(&self) -> &str {
+        &self.event
+    }
+
+    fn data(&self) -> &str {
+        &self.data
+    }
+}
+
+pub fn parse_cursor(cursor: &str) -> Option<(bool, u64)> {
+    if cursor.starts_with("before-") {
+        cursor["before-".len()..].parse().ok().map(|id| (true, id))
+    } else if cursor.starts_with("after-") {
+        cursor["after-".len()..].parse().ok().map(|id| (false, id))
+    } else {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl EntityStore for MemoryStore {
+    async fn get(&mut self, path: String, _local: bool) -> Result<Option<StoreItem>, StoreError> {
+        let data = self.0.lock().unwrap();
+
+        Ok(data.entities.get(&path).cloned())
+    }
+
+    async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
+        let mut data = self.0.lock().unwrap();
+        data.entities.insert(path, item.clone());
+
+        Ok(())
+    }
+
+    async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
+        let data = self.0.lock().unwrap();
+        let documents: Vec<_> = data.entities.values().map(StoreItem::to_json).collect();
+
+        Ok(query::evaluate(&documents, &query))
+    }
+
+    async fn read_collection(
+        &mut self,
+        path: String,
+        count: Option<u32>,
+        cursor: Option<String>,
+    ) -> Result<CollectionPointer, StoreError> {
+        let data = self.0.lock().unwrap();
+        let empty = Vec::new();
+        let items = data.collections.get(&path).unwrap_or(&empty);
+        let count = count.unwrap_or(20) as usize;
+
+        // Collections are read newest-first, like the cellar backend does.
+        let mut newest_first: Vec<_> = items.iter().rev().collect();
+        match cursor.as_ref().and_then(|cursor| parse_cursor(cursor)) {
+            Some((true, id)) => newest_first.retain(|(item_id, _)| *item_id < id),
+            Some((false, id)) => {
+                newest_first.retain(|(item_id, _)| *item_id > id);
+                let skip = newest_first.len().saturating_sub(count);
+                newest_first.drain(..skip);
+            }
+            None => {}
+        }
+
+        newest_first.truncate(count);
+
+        Ok(CollectionPointer {
+            items: newest_first
+                .iter()
+                .map(|(_, item)| item.to_owned())
+                .collect(),
+            after: newest_first.first().map(|(id, _)| format!("after-{}", id)),
+            before: newest_first.last().map(|(id, _)| format!("before-{}", id)),
+            count: Some(items.len() as u32),
+        })
+    }
+
+    async fn find_collection(
+        &mut self,
+        path: String,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let data = self.0.lock().unwrap();
+        let found = data
+            .collections
+            .get(&path)
+            .and_then(|items| items.iter().find(|(_, value)| *value == item));
+
+        Ok(CollectionPointer {
+            items: found.iter().map(|(_, item)| item.to_owned()).collect(),
+            after: found.map(|(id, _)| format!("after-{}", id)),
+            before: found.map(|(id, _)| format!("before-{}", id)),
+            count: None,
+        })
+    }
+
+    async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        let mut data = self.0.lock().unwrap();
+        data.collection_counter += 1;
+        let id = data.collection_counter;
+
+        let items = data.collections.entry(path).or_insert_with(Vec::new);
+        if !items.iter().any(|(_, value)| *value == item) {
+            items.push((id, item));
+        }
+
+        Ok(())
+    }
+
+    async fn read_collection_inverse(
+        &mut self,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let data = self.0.lock().unwrap();
+        let items: Vec<String> = data
+            .collections
+            .iter()
+            .filter(|(_, items)| items.iter().any(|(_, value)| *value == item))
+            .map(|(path, _)| path.to_owned())
+            .collect();
+
+        Ok(CollectionPointer {
+            count: Some(items.len() as u32),
+            items,
+            after: None,
+            before: None,
+        })
+    }
+
+    async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        let mut data = self.0.lock().unwrap();
+        if let Some(items) = data.collections.get_mut(&path) {
+            items.retain(|(_, value)| *value != item);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl QueueStore for MemoryStore {
+    async fn get_item(&mut self) -> Result<Option<Box<dyn QueueItem + Send>>, StoreError> {
+        let mut data = self.0.lock().unwrap();
+
+        Ok(data
+            .queue
+            .pop_front()
+            .map(|item| Box::new(item) as Box<dyn QueueItem + Send>))
+    }
+
+    async fn mark_success(&mut self, _item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn mark_failure(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        let mut data = self.0.lock().unwrap();
+        data.queue.push_back(MemoryQueueItem {
+            event: item.event().to_owned(),
+            data: item.data().to_owned(),
+        });
+
+        Ok(())
+    }
+
+    async fn add(&mut self, event: String, data: String) -> Result<(), StoreError> {
+        let mut queue = self.0.lock().unwrap();
+        queue.queue.push_back(MemoryQueueItem { event, data });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+    use kroeg_tap::{QueryId, QueryObject};
+    use serde_json::json;
+
+    const NOTE: &str = "https://www.w3.org/ns/activitystreams#Note";
+
+    fn note(id: &str) -> StoreItem {
+        StoreItem::parse(id, &json!([{ "@id": id, "@type": [NOTE] }])).unwrap()
+    }
+
+    #[test]
+    fn stores_entities() {
+        let mut store = MemoryStore::new();
+        let id = "https://example.com/notes/1".to_owned();
+
+        block_on(async {
+            assert!(store.get(id.to_owned(), true).await.unwrap().is_none());
+
+            store.put(id.to_owned(), &mut note(&id)).await.unwrap();
+            let item = store.get(id.to_owned(), true).await.unwrap().unwrap();
+            assert_eq!(item.id(), id);
+
+            // Clones share their data, like connections leased from one pool.
+            let mut other = store.clone();
+            assert!(other.get(id.to_owned(), true).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn pages_through_collections() {
+        let mut store = MemoryStore::new();
+        let outbox = "https://example.com/outbox".to_owned();
+
+        block_on(async {
+            for index in 0..5 {
+                let item = format!("https://example.com/notes/{}", index);
+                store
+                    .insert_collection(outbox.to_owned(), item)
+                    .await
+                    .unwrap();
+            }
+
+            // Inserting an item twice keeps a single copy.
+            store
+                .insert_collection(outbox.to_owned(), "https://example.com/notes/0".to_owned())
+                .await
+                .unwrap();
+
+            let first = store
+                .read_collection(outbox.to_owned(), Some(2), None)
+                .await
+                .unwrap();
+            assert_eq!(first.count, Some(5));
+            assert_eq!(
+                first.items,
+                vec!["https://example.com/notes/4", "https://example.com/notes/3"]
+            );
+
+            let second = store
+                .read_collection(outbox.to_owned(), Some(2), first.before)
+                .await
+                .unwrap();
+            assert_eq!(
+                second.items,
+                vec!["https://example.com/notes/2", "https://example.com/notes/1"]
+            );
+
+            let back = store
+                .read_collection(outbox.to_owned(), Some(2), second.after)
+                .await
+                .unwrap();
+            assert_eq!(back.items, first.items);
+
+            store
+                .remove_collection(outbox.to_owned(), "https://example.com/notes/4".to_owned())
+                .await
+                .unwrap();
+            let found = store
+                .find_collection(outbox.to_owned(), "https://example.com/notes/4".to_owned())
+                .await
+                .unwrap();
+            assert!(found.items.is_empty());
+
+            let inverse = store
+                .read_collection_inverse("https://example.com/notes/3".to_owned())
+                .await
+                .unwrap();
+            assert_eq!(inverse.items, vec![outbox.to_owned()]);
+        });
+    }
+
+    #[test]
+    fn queues_in_order_and_requeues_failures() {
+        let mut store = MemoryStore::new();
+
+        block_on(async {
+            store
+                .add("deliver".to_owned(), "1".to_owned())
+                .await
+                .unwrap();
+            store
+                .add("deliver".to_owned(), "2".to_owned())
+                .await
+                .unwrap();
+            assert_eq!(store.queue_depth(), 2);
+
+            let first = store.get_item().await.unwrap().unwrap();
+            assert_eq!((first.event(), first.data()), ("deliver", "1"));
+            store.mark_failure(first).await.unwrap();
+
+            let second = store.get_item().await.unwrap().unwrap();
+            assert_eq!(second.data(), "2");
+            store.mark_success(second).await.unwrap();
+
+            let retried = store.get_item().await.unwrap().unwrap();
+            assert_eq!(retried.data(), "1");
+            store.mark_success(retried).await.unwrap();
+
+            assert!(store.get_item().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn queries_stored_entities() {
+        let mut store = MemoryStore::new();
+
+        block_on(async {
+            for id in &["https://example.com/notes/1", "https://example.com/notes/2"] {
+                store.put(id.to_string(), &mut note(id)).await.unwrap();
+            }
+
+            let mut rows = store
+                .query(vec![QuadQuery(
+                    QueryId::Placeholder(0),
+                    QueryId::Value("http://www.w3.org/1999/02/22-rdf-syntax-ns#type".to_owned()),
+                    QueryObject::Id(QueryId::Value(NOTE.to_owned())),
+                )])
+                .await
+                .unwrap();
+            rows.sort();
+
+            assert_eq!(
+                rows,
+                vec![
+                    vec!["https://example.com/notes/1".to_owned()],
+                    vec!["https://example.com/notes/2".to_owned()],
+                ]
+            );
+        });
+    }
+}
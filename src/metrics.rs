@@ -0,0 +1,404 @@
+use crate::database::DatabasePool;
+use crate::supervisor::WorkerStatus;
+use http::Response;
+use http_service::Body;
+use kroeg_server::{router::RequestHandler, ServerError};
+use kroeg_tap::{
+    CollectionPointer, Context, EntityStore, QuadQuery, QueueItem, QueueStore, StoreError,
+    StoreItem,
+};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+const BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Clone)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: vec![0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+fn labels(base: &str, extra: &str) -> String {
+    match (base.is_empty(), extra.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => format!("{{{}}}", base),
+        (true, false) => format!("{{{}}}", extra),
+        (false, false) => format!("{{{},{}}}", base, extra),
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        for (count, bound) in self.buckets.iter().zip(BUCKETS) {
+            let le = format!("le=\"{}\"", bound);
+            writeln!(out, "{}_bucket{} {}", name, labels(label, &le), count).unwrap();
+        }
+
+        writeln!(
+            out,
+            "{}_bucket{} {}",
+            name,
+            labels(label, "le=\"+Inf\""),
+            self.count
+        )
+        .unwrap();
+        writeln!(out, "{}_sum{} {}", name, labels(label, ""), self.sum).unwrap();
+        writeln!(out, "{}_count{} {}", name, labels(label, ""), self.count).unwrap();
+    }
+}
+
+#[derive(Default)]
+struct MetricsData {
+    requests: BTreeMap<(String, String), Histogram>,
+    store: BTreeMap<&'static str, Histogram>,
+    lease: Histogram,
+    deliveries: BTreeMap<(String, &'static str), u64>,
+}
+
+/// Collects the numbers behind `/-/metrics`. Clones share the same data.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<MetricsData>>);
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    fn request(&self, route: &str, status: &str, duration: Duration) {
+        let mut data = self.0.lock().unwrap();
+        data.requests
+            .entry((route.to_owned(), status.to_owned()))
+            .or_default()
+            .observe(duration);
+    }
+
+    fn store(&self, operation: &'static str, duration: Duration) {
+        let mut data = self.0.lock().unwrap();
+        data.store.entry(operation).or_default().observe(duration);
+    }
+
+    pub fn lease(&self, duration: Duration) {
+        self.0.lock().unwrap().lease.observe(duration);
+    }
+
+    fn delivery(&self, item: &dyn QueueItem, result: &'static str) {
+        let host = delivery_host(item.data());
+
+        let mut data = self.0.lock().unwrap();
+        *data.deliveries.entry((host, result)).or_default() += 1;
+    }
+
+    /// Renders everything in the Prometheus text format.
+    pub fn render(&self, queue_depth: Option<u64>, workers: &WorkerStatus) -> String {
+        let data = self.0.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# TYPE kroeg_http_requests_total counter\n");
+        for ((route, status), histogram) in &data.requests {
+            writeln!(
+                out,
+                "kroeg_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                escape(route),
+                status,
+                histogram.count
+            )
+            .unwrap();
+        }
+
+        out.push_str("# TYPE kroeg_http_request_duration_seconds histogram\n");
+        for ((route, status), histogram) in &data.requests {
+            let label = format!("route=\"{}\",status=\"{}\"", escape(route), status);
+            histogram.render(&mut out, "kroeg_http_request_duration_seconds", &label);
+        }
+
+        out.push_str("# TYPE kroeg_store_operation_duration_seconds histogram\n");
+        for (operation, histogram) in &data.store {
+            let label = format!("operation=\"{}\"", operation);
+            histogram.render(&mut out, "kroeg_store_operation_duration_seconds", &label);
+        }
+
+        out.push_str("# TYPE kroeg_database_lease_duration_seconds histogram\n");
+        data.lease
+            .render(&mut out, "kroeg_database_lease_duration_seconds", "");
+
+        if let Some(depth) = queue_depth {
+            out.push_str("# TYPE kroeg_delivery_queue_depth gauge\n");
+            writeln!(out, "kroeg_delivery_queue_depth {}", depth).unwrap();
+        }
+
+        out.push_str("# TYPE kroeg_deliveries_total counter\n");
+        for ((host, result), count) in &data.deliveries {
+            writeln!(
+                out,
+                "kroeg_deliveries_total{{host=\"{}\",result=\"{}\"}} {}",
+                escape(host),
+                result,
+                count
+            )
+            .unwrap();
+        }
+
+        out.push_str("# TYPE kroeg_delivery_workers gauge\n");
+        writeln!(out, "kroeg_delivery_workers {}", workers.live()).unwrap();
+        out.push_str("# TYPE kroeg_delivery_workers_configured gauge\n");
+        writeln!(out, "kroeg_delivery_workers_configured {}", workers.total()).unwrap();
+
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The host a queue item is delivered to, given its data. That is either the data itself, as a
+///  plain or JSON string, or the `inbox` of the data as a JSON object. Anything else is counted as `unknown`, rather than guessing from
+///  whichever URL comes first, which could just as well be the actor's.
+pub fn delivery_host(data: &str) -> String {
+    let target = match serde_json::from_str::<Value>(data) {
+        Ok(Value::String(target)) => Some(target),
+        Ok(Value::Object(map)) => map.get("inbox").and_then(Value::as_str).map(str::to_owned),
+        _ => Some(data.to_owned()),
+    };
+
+    target
+        .and_then(|target| Url::parse(&target).ok())
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Wraps a route's handler, recording how many requests it answered and how long they took.
+pub struct MeteredHandler {
+    inner: Box<dyn RequestHandler>,
+    route: String,
+    metrics: Metrics,
+}
+
+impl MeteredHandler {
+    pub fn new(inner: Box<dyn RequestHandler>, route: String, metrics: Metrics) -> MeteredHandler {
+        MeteredHandler {
+            inner,
+            route,
+            metrics,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for MeteredHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let start = Instant::now();
+        let response = self.inner.run(context, request).await;
+
+        let status = match &response {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(_) => "error".to_owned(),
+        };
+
+        self.metrics.request(&self.route, &status, start.elapsed());
+
+        response
+    }
+}
+
+/// `/-/metrics`, in the Prometheus text format.
+pub struct MetricsHandler {
+    pub metrics: Metrics,
+    pub pool: DatabasePool,
+    pub workers: WorkerStatus,
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for MetricsHandler {
+    async fn run(
+        &self,
+        _: &mut Context<'_, '_>,
+        _: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let queue_depth = match self.pool.queue_depth().await {
+            Ok(depth) => Some(depth),
+            Err(e) => {
+                log::warn!("Failed to read delivery queue depth: {}", e);
+                None
+            }
+        };
+
+        Ok(Response::builder()
+            .status(200)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(self.metrics.render(queue_depth, &self.workers)))
+            .unwrap())
+    }
+}
+
+/// Wraps an entity and queue store, timing every entity store operation and counting delivery
+///  results per remote host.
+pub struct MeteredStore<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S> MeteredStore<S> {
+    pub fn new(inner: S, metrics: Metrics) -> MeteredStore<S> {
+        MeteredStore { inner, metrics }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: EntityStore> EntityStore for MeteredStore<S> {
+    async fn get(&mut self, path: String, local: bool) -> Result<Option<StoreItem>, StoreError> {
+        let start = Instant::now();
+        let result = self.inner.get(path, local).await;
+        self.metrics.store("get", start.elapsed());
+
+        result
+    }
+
+    async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.inner.put(path, item).await;
+        self.metrics.store("put", start.elapsed());
+
+        result
+    }
+
+    async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
+        let start = Instant::now();
+        let result = self.inner.query(query).await;
+        self.metrics.store("query", start.elapsed());
+
+        result
+    }
+
+    async fn read_collection(
+        &mut self,
+        path: String,
+        count: Option<u32>,
+        cursor: Option<String>,
+    ) -> Result<CollectionPointer, StoreError> {
+        let start = Instant::now();
+        let result = self.inner.read_collection(path, count, cursor).await;
+        self.metrics.store("read_collection", start.elapsed());
+
+        result
+    }
+
+    async fn find_collection(
+        &mut self,
+        path: String,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let start = Instant::now();
+        let result = self.inner.find_collection(path, item).await;
+        self.metrics.store("find_collection", start.elapsed());
+
+        result
+    }
+
+    async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.inner.insert_collection(path, item).await;
+        self.metrics.store("insert_collection", start.elapsed());
+
+        result
+    }
+
+    async fn read_collection_inverse(
+        &mut self,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let start = Instant::now();
+        let result = self.inner.read_collection_inverse(item).await;
+        self.metrics
+            .store("read_collection_inverse", start.elapsed());
+
+        result
+    }
+
+    async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.inner.remove_collection(path, item).await;
+        self.metrics.store("remove_collection", start.elapsed());
+
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: QueueStore> QueueStore for MeteredStore<S> {
+    async fn get_item(&mut self) -> Result<Option<Box<dyn QueueItem + Send>>, StoreError> {
+        self.inner.get_item().await
+    }
+
+    async fn mark_success(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        self.metrics.delivery(&*item, "success");
+        self.inner.mark_success(item).await
+    }
+
+    async fn mark_failure(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        self.metrics.delivery(&*item, "failure");
+        self.inner.mark_failure(item).await
+    }
+
+    async fn add(&mut self, event: String, data: String) -> Result<(), StoreError> {
+        self.inner.add(event, data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deliveries_count_against_their_inbox() {
+        assert_eq!(delivery_host("https://a.example/inbox"), "a.example");
+        assert_eq!(
+            delivery_host(
+                r#"{"actor": "https://a.example/actor", "inbox": "https://b.example/inbox"}"#
+            ),
+            "b.example"
+        );
+        assert_eq!(
+            delivery_host(r#"{"actor": "https://a.example/actor"}"#),
+            "unknown"
+        );
+        assert_eq!(delivery_host("1"), "unknown");
+    }
+}
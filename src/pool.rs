@@ -0,0 +1,297 @@
+use crate::config::PoolConfig;
+use kroeg_tap::StoreError;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Knows how to open new connections for a `Pool`, and how to tell whether an idle one still works.
+#[async_trait::async_trait]
+pub trait Manager: Send + Sync + 'static {
+    type Connection: Send + 'static;
+
+    async fn connect(&self) -> Result<Self::Connection, StoreError>;
+    async fn check(&self, connection: &mut Self::Connection) -> Result<(), StoreError>;
+}
+
+struct PoolState<C> {
+    idle: VecDeque<(C, Instant)>,
+    open: usize,
+    waiters: VecDeque<Waker>,
+}
+
+struct PoolInner<M: Manager> {
+    manager: M,
+    config: PoolConfig,
+    state: Mutex<PoolState<M::Connection>>,
+}
+
+impl<M: Manager> PoolInner<M> {
+    fn release(&self, connection: Option<M::Connection>) {
+        let mut state = self.state.lock().unwrap();
+        match connection {
+            Some(connection) => state.idle.push_back((connection, Instant::now())),
+            None => state.open -= 1,
+        }
+
+        // Wake everyone, as some of the waiters may have given up already.
+        for waker in state.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A bounded pool of connections, which hands out idle connections before opening new ones.
+pub struct Pool<M: Manager>(Arc<PoolInner<M>>);
+
+impl<M: Manager> Clone for Pool<M> {
+    fn clone(&self) -> Self {
+        Pool(self.0.clone())
+    }
+}
+
+// A slot counted in `open` that hasn't been handed out yet. Dropping it frees the slot again, so a
+//  lease given up halfway, like when `get` times out during `connect`, doesn't leak it.
+struct Reservation<'a, M: Manager>(Option<&'a PoolInner<M>>);
+
+impl<'a, M: Manager> Reservation<'a, M> {
+    fn fulfil(mut self, pool: &Arc<PoolInner<M>>, connection: M::Connection) -> Pooled<M> {
+        self.0 = None;
+
+        Pooled {
+            connection: Some(connection),
+            pool: pool.clone(),
+        }
+    }
+}
+
+impl<'a, M: Manager> Drop for Reservation<'a, M> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.0 {
+            inner.release(None);
+        }
+    }
+}
+
+enum Slot<C> {
+    Idle(C),
+    Open,
+}
+
+// Resolves once a connection can be taken from the pool, or a new one may be opened.
+struct WaitForSlot<'a, M: Manager>(&'a PoolInner<M>);
+
+impl<'a, M: Manager> Future for WaitForSlot<'a, M> {
+    type Output = Slot<M::Connection>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = self.0;
+        let mut state = inner.state.lock().unwrap();
+
+        if let Some((connection, _)) = state.idle.pop_back() {
+            return Poll::Ready(Slot::Idle(connection));
+        }
+
+        if state.open < inner.config.max_connections {
+            state.open += 1;
+            return Poll::Ready(Slot::Open);
+        }
+
+        state.waiters.push_back(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<M: Manager> Pool<M> {
+    pub fn new(manager: M, config: PoolConfig) -> Pool<M> {
+        Pool(Arc::new(PoolInner {
+            manager,
+            config,
+            state: Mutex::new(PoolState {
+                idle: VecDeque::new(),
+                open: 0,
+                waiters: VecDeque::new(),
+            }),
+        }))
+    }
+
+    pub fn manager(&self) -> &M {
+        &self.0.manager
+    }
+
+    async fn acquire(&self) -> Result<Pooled<M>, StoreError> {
+        loop {
+            let slot = WaitForSlot(&self.0).await;
+            let reservation = Reservation(Some(&*self.0));
+
+            match slot {
+                // If the connection broke while idle, the reservation forgets it, and the next one
+                //  is tried.
+                Slot::Idle(mut connection) => {
+                    if self.0.manager.check(&mut connection).await.is_ok() {
+                        return Ok(reservation.fulfil(&self.0, connection));
+                    }
+                }
+
+                Slot::Open => {
+                    let connection = self.0.manager.connect().await?;
+                    return Ok(reservation.fulfil(&self.0, connection));
+                }
+            }
+        }
+    }
+
+    /// Leases a connection, waiting at most `acquire_timeout` seconds for one to become free.
+    pub async fn get(&self) -> Result<Pooled<M>, StoreError> {
+        let timeout = Duration::from_secs(self.0.config.acquire_timeout);
+
+        match async_std::future::timeout(timeout, self.acquire()).await {
+            Ok(result) => result,
+            Err(_) => Err("timed out waiting for a database connection".into()),
+        }
+    }
+
+    /// Closes connections that have been idle for longer than `idle_timeout`, and opens new ones
+    ///  until there are at least `min_idle` idle connections.
+    pub async fn maintain(&self) -> Result<(), StoreError> {
+        let idle_timeout = Duration::from_secs(self.0.config.idle_timeout);
+
+        let missing = {
+            let mut state = self.0.state.lock().unwrap();
+            let min_idle = self.0.config.min_idle;
+
+            while state.idle.len() > min_idle
+                && state.idle.front().map(|(_, since)| since.elapsed()) > Some(idle_timeout)
+            {
+                state.idle.pop_front();
+                state.open -= 1;
+            }
+
+            let missing = min_idle
+                .saturating_sub(state.idle.len())
+                .min(self.0.config.max_connections - state.open);
+            state.open += missing;
+            missing
+        };
+
+        for opened in 0..missing {
+            match self.0.manager.connect().await {
+                Ok(connection) => self.0.release(Some(connection)),
+                Err(e) => {
+                    for _ in opened..missing {
+                        self.0.release(None);
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A connection leased from a `Pool`. It is handed back to the pool when dropped.
+pub struct Pooled<M: Manager> {
+    connection: Option<M::Connection>,
+    pool: Arc<PoolInner<M>>,
+}
+
+impl<M: Manager> Deref for Pooled<M> {
+    type Target = M::Connection;
+
+    fn deref(&self) -> &M::Connection {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl<M: Manager> DerefMut for Pooled<M> {
+    fn deref_mut(&mut self) -> &mut M::Connection {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl<M: Manager> Drop for Pooled<M> {
+    fn drop(&mut self) {
+        self.pool.release(self.connection.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Hangs on the first connect, as an unreachable database would, and succeeds after that.
+    struct HangingManager(AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl Manager for HangingManager {
+        type Connection = ();
+
+        async fn connect(&self) -> Result<(), StoreError> {
+            if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                futures::future::pending::<()>().await;
+            }
+
+            Ok(())
+        }
+
+        async fn check(&self, _: &mut ()) -> Result<(), StoreError> {
+            Ok(())
+        }
+    }
+
+    fn config(max_connections: usize) -> PoolConfig {
+        PoolConfig {
+            max_connections,
+            min_idle: 0,
+            idle_timeout: 600,
+            acquire_timeout: 1,
+        }
+    }
+
+    fn open(pool: &Pool<HangingManager>) -> usize {
+        pool.0.state.lock().unwrap().open
+    }
+
+    #[test]
+    fn cancelled_connect_frees_its_slot() {
+        let pool = Pool::new(HangingManager(AtomicUsize::new(0)), config(1));
+
+        block_on(async {
+            assert!(pool.get().await.is_err());
+            assert_eq!(open(&pool), 0);
+
+            let connection = pool.get().await.unwrap();
+            assert_eq!(open(&pool), 1);
+
+            drop(connection);
+            assert_eq!(pool.0.state.lock().unwrap().idle.len(), 1);
+        });
+    }
+
+    #[test]
+    fn waits_for_a_free_connection() {
+        let pool = Pool::new(HangingManager(AtomicUsize::new(1)), config(1));
+
+        block_on(async {
+            let first = pool.get().await.unwrap();
+
+            let waiting = async_std::task::spawn({
+                let pool = pool.clone();
+                async move { pool.get().await.map(|_| ()) }
+            });
+
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            drop(first);
+
+            assert!(waiting.await.is_ok());
+            assert_eq!(open(&pool), 1);
+        });
+    }
+}
@@ -0,0 +1,415 @@
+use kroeg_tap::{QuadQuery, QueryId, QueryObject};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+const RDF_TYPE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#type";
+
+enum Object {
+    Id(String),
+    Value {
+        value: String,
+        type_id: Option<String>,
+        language: Option<String>,
+    },
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_owned(),
+        other => other.to_string(),
+    }
+}
+
+// Walks an expanded JSON-LD document, collecting every (subject, predicate, object) triple.
+fn collect_triples(value: &Value, triples: &mut Vec<(String, String, Object)>) {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                collect_triples(item, triples);
+            }
+        }
+
+        Value::Object(map) => {
+            if let Some(graph) = map.get("@graph") {
+                collect_triples(graph, triples);
+            }
+
+            let subject = match map.get("@id") {
+                Some(Value::String(id)) => id.to_owned(),
+                _ => return,
+            };
+
+            for (key, values) in map {
+                if key == "@type" {
+                    for typ in values.as_array().into_iter().flatten() {
+                        triples.push((
+                            subject.to_owned(),
+                            RDF_TYPE.to_owned(),
+                            Object::Id(value_to_string(typ)),
+                        ));
+                    }
+
+                    continue;
+                }
+
+                if key.starts_with('@') {
+                    continue;
+                }
+
+                for value in values.as_array().into_iter().flatten() {
+                    if let Some(id) = value.get("@id") {
+                        triples.push((
+                            subject.to_owned(),
+                            key.to_owned(),
+                            Object::Id(value_to_string(id)),
+                        ));
+
+                        collect_triples(value, triples);
+                    } else if let Some(inner) = value.get("@value") {
+                        triples.push((
+                            subject.to_owned(),
+                            key.to_owned(),
+                            Object::Value {
+                                value: value_to_string(inner),
+                                type_id: value.get("@type").map(value_to_string),
+                                language: value.get("@language").map(value_to_string),
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+
+        _ => {}
+    }
+}
+
+type Bindings = BTreeMap<usize, String>;
+
+type Triple = (String, String, Object);
+
+// The triples of every document, indexed by subject and predicate so a pattern with either bound
+//  only looks at the triples that can match it.
+struct Triples {
+    all: Vec<Triple>,
+    every: Vec<usize>,
+    by_subject: HashMap<String, Vec<usize>>,
+    by_predicate: HashMap<String, Vec<usize>>,
+}
+
+impl Triples {
+    fn new(all: Vec<Triple>) -> Triples {
+        let mut by_subject: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut by_predicate: HashMap<String, Vec<usize>> = HashMap::new();
+
+        for (index, (subject, predicate, _)) in all.iter().enumerate() {
+            by_subject
+                .entry(subject.to_owned())
+                .or_default()
+                .push(index);
+            by_predicate
+                .entry(predicate.to_owned())
+                .or_default()
+                .push(index);
+        }
+
+        Triples {
+            every: (0..all.len()).collect(),
+            all,
+            by_subject,
+            by_predicate,
+        }
+    }
+
+    fn candidates(&self, subject: &QueryId, predicate: &QueryId, bindings: &Bindings) -> &[usize] {
+        let index = if let Some(subject) = resolve(subject, bindings) {
+            self.by_subject.get(subject)
+        } else if let Some(predicate) = resolve(predicate, bindings) {
+            self.by_predicate.get(predicate)
+        } else {
+            return &self.every;
+        };
+
+        index.map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
+// The single value a query term can still match, if it is fixed already.
+fn resolve<'a>(query: &'a QueryId, bindings: &'a Bindings) -> Option<&'a str> {
+    match query {
+        QueryId::Value(value) => Some(value),
+        QueryId::Placeholder(index) => bindings.get(&(*index as usize)).map(String::as_str),
+        _ => None,
+    }
+}
+
+fn bind(query: &QueryId, value: &str, bindings: &mut Bindings) -> bool {
+    match query {
+        QueryId::Value(expected) => expected == value,
+        QueryId::Any(expected) => expected.iter().any(|item| item == value),
+        QueryId::Ignore => true,
+        QueryId::Placeholder(index) => {
+            let index = *index as usize;
+            match bindings.get(&index) {
+                Some(bound) => bound == value,
+                None => {
+                    bindings.insert(index, value.to_owned());
+                    true
+                }
+            }
+        }
+    }
+}
+
+fn bind_object(query: &QueryObject, object: &Object, bindings: &mut Bindings) -> bool {
+    match (query, object) {
+        (QueryObject::Id(query), Object::Id(id)) => bind(query, id, bindings),
+        (
+            QueryObject::Object { value, type_id },
+            Object::Value {
+                value: actual,
+                type_id: actual_type,
+                language: None,
+            },
+        ) => {
+            value == actual
+                && bind(
+                    type_id,
+                    actual_type
+                        .as_ref()
+                        .map(String::as_str)
+                        .unwrap_or("http://www.w3.org/2001/XMLSchema#string"),
+                    bindings,
+                )
+        }
+        (
+            QueryObject::LanguageString { value, language },
+            Object::Value {
+                value: actual,
+                language: Some(actual_language),
+                ..
+            },
+        ) => value == actual && language == actual_language,
+
+        _ => false,
+    }
+}
+
+fn solve(
+    queries: &[QuadQuery],
+    triples: &Triples,
+    bindings: Bindings,
+    results: &mut Vec<Bindings>,
+) {
+    let (QuadQuery(subject, predicate, object), rest) = match queries.split_first() {
+        Some(split) => split,
+        None => {
+            results.push(bindings);
+            return;
+        }
+    };
+
+    for &index in triples.candidates(subject, predicate, &bindings) {
+        let (s, p, o) = &triples.all[index];
+        let mut attempt = bindings.clone();
+        if bind(subject, s, &mut attempt)
+            && bind(predicate, p, &mut attempt)
+            && bind_object(object, o, &mut attempt)
+        {
+            solve(rest, triples, attempt, results);
+        }
+    }
+}
+
+// How a term can be found in a document stored as JSON text. Terms JSON would escape can't be.
+fn searchable(term: &str) -> Option<String> {
+    if term.is_empty() || term.chars().any(|c| c == '"' || c == '\\' || c < ' ') {
+        None
+    } else {
+        Some(term.to_owned())
+    }
+}
+
+fn id_terms(query: &QueryId) -> Option<Vec<String>> {
+    match query {
+        QueryId::Value(value) => searchable(value).map(|term| vec![term]),
+        QueryId::Any(values) => values.iter().map(|value| searchable(value)).collect(),
+        _ => None,
+    }
+}
+
+/// Strings of which any document that can match `query` contains at least one in its JSON text,
+///  so backends storing documents as text only need to load those. Returns `None` if some
+///  pattern could match any document.
+pub fn search_terms(query: &[QuadQuery]) -> Option<Vec<String>> {
+    if query.is_empty() {
+        return None;
+    }
+
+    let mut terms = Vec::new();
+    for QuadQuery(subject, predicate, object) in query {
+        let object_terms = match object {
+            QueryObject::Id(id) => id_terms(id),
+            QueryObject::Object { value, .. } | QueryObject::LanguageString { value, .. } => {
+                searchable(value).map(|term| vec![term])
+            }
+        };
+
+        // Types are stored under `@type`, so the predicate itself never shows up.
+        let predicate_terms = match predicate {
+            QueryId::Value(value) if value == RDF_TYPE => None,
+            predicate => id_terms(predicate),
+        };
+
+        terms.extend(id_terms(subject).or(object_terms).or(predicate_terms)?);
+    }
+
+    Some(terms)
+}
+
+/// Evaluates a quad query against a set of expanded JSON-LD documents, for backends that have no
+///  query engine of their own. Every row holds the placeholder values in placeholder order.
+pub fn evaluate<'a>(
+    documents: impl IntoIterator<Item = &'a Value>,
+    query: &[QuadQuery],
+) -> Vec<Vec<String>> {
+    let mut triples = Vec::new();
+    for document in documents {
+        collect_triples(document, &mut triples);
+    }
+
+    let mut results = Vec::new();
+    solve(query, &Triples::new(triples), Bindings::new(), &mut results);
+
+    results
+        .into_iter()
+        .map(|bindings| bindings.into_iter().map(|(_, value)| value).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NOTE: &str = "https://www.w3.org/ns/activitystreams#Note";
+    const ATTRIBUTED_TO: &str = "https://www.w3.org/ns/activitystreams#attributedTo";
+
+    fn documents() -> Vec<Value> {
+        vec![
+            json!([{
+                "@id": "https://example.com/notes/1",
+                "@type": [NOTE],
+                ATTRIBUTED_TO: [{ "@id": "https://example.com/alice" }],
+            }]),
+            json!([{
+                "@id": "https://example.com/notes/2",
+                "@type": [NOTE],
+                ATTRIBUTED_TO: [{ "@id": "https://example.com/bob" }],
+            }]),
+            json!([{
+                "@id": "https://example.com/alice",
+                "@type": ["https://www.w3.org/ns/activitystreams#Person"],
+                "https://www.w3.org/ns/activitystreams#name": [{ "@value": "Alice" }],
+            }]),
+        ]
+    }
+
+    #[test]
+    fn joins_on_placeholders() {
+        let query = vec![
+            QuadQuery(
+                QueryId::Placeholder(0),
+                QueryId::Value(RDF_TYPE.to_owned()),
+                QueryObject::Id(QueryId::Value(NOTE.to_owned())),
+            ),
+            QuadQuery(
+                QueryId::Placeholder(0),
+                QueryId::Value(ATTRIBUTED_TO.to_owned()),
+                QueryObject::Id(QueryId::Value("https://example.com/alice".to_owned())),
+            ),
+        ];
+
+        assert_eq!(
+            evaluate(&documents(), &query),
+            vec![vec!["https://example.com/notes/1".to_owned()]]
+        );
+    }
+
+    #[test]
+    fn matches_values_by_type() {
+        let query = vec![QuadQuery(
+            QueryId::Placeholder(0),
+            QueryId::Value("https://www.w3.org/ns/activitystreams#name".to_owned()),
+            QueryObject::Object {
+                value: "Alice".to_owned(),
+                type_id: QueryId::Ignore,
+            },
+        )];
+
+        assert_eq!(
+            evaluate(&documents(), &query),
+            vec![vec!["https://example.com/alice".to_owned()]]
+        );
+    }
+
+    #[test]
+    fn search_terms_cover_every_pattern() {
+        let query = vec![
+            QuadQuery(
+                QueryId::Placeholder(0),
+                QueryId::Value(RDF_TYPE.to_owned()),
+                QueryObject::Id(QueryId::Value(NOTE.to_owned())),
+            ),
+            QuadQuery(
+                QueryId::Placeholder(1),
+                QueryId::Value("https://www.w3.org/ns/activitystreams#name".to_owned()),
+                QueryObject::Object {
+                    value: "Alice".to_owned(),
+                    type_id: QueryId::Ignore,
+                },
+            ),
+        ];
+
+        let terms = search_terms(&query).unwrap();
+        assert_eq!(terms, vec![NOTE.to_owned(), "Alice".to_owned()]);
+
+        // Every document a pattern can match contains one of the terms.
+        for document in documents() {
+            let text = document.to_string();
+            let matches = query.iter().any(|pattern| {
+                !evaluate(std::iter::once(&document), std::slice::from_ref(pattern)).is_empty()
+            });
+
+            assert!(!matches || terms.iter().any(|term| text.contains(term.as_str())));
+        }
+    }
+
+    #[test]
+    fn search_terms_give_up_on_open_patterns() {
+        let query = vec![QuadQuery(
+            QueryId::Placeholder(0),
+            QueryId::Placeholder(1),
+            QueryObject::Id(QueryId::Placeholder(2)),
+        )];
+
+        assert_eq!(search_terms(&query), None);
+    }
+
+    #[test]
+    fn unbound_patterns_scan_everything() {
+        let query = vec![QuadQuery(
+            QueryId::Placeholder(0),
+            QueryId::Placeholder(1),
+            QueryObject::Id(QueryId::Value("https://example.com/bob".to_owned())),
+        )];
+
+        assert_eq!(
+            evaluate(&documents(), &query),
+            vec![vec![
+                "https://example.com/notes/2".to_owned(),
+                ATTRIBUTED_TO.to_owned()
+            ]]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use crate::config::{Limit, RouteLimits};
+use crate::routes;
+use crate::service::client_address;
+use http::Response;
+use http_service::Body;
+use kroeg_server::{router::RequestHandler, ServerError};
+use kroeg_tap::Context;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Once a limit has this many buckets, the ones that have filled up again are dropped to make room
+//  for new clients, and if that isn't enough, the tenth that was used longest ago.
+const MAX_BUCKETS: usize = 10_000;
+const EVICTED_BUCKETS: usize = MAX_BUCKETS / 10;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A set of token buckets sharing one limit, keyed by whoever is being limited.
+#[derive(Clone)]
+struct Limiter {
+    limit: Limit,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl Limiter {
+    fn new(limit: Limit) -> Limiter {
+        Limiter {
+            limit,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        f64::from(self.limit.per_minute) / 60.0
+    }
+
+    /// Takes a token from `key`'s bucket. If it's empty, returns how many seconds until it isn't.
+    fn take(&self, key: &str) -> Result<(), u64> {
+        let burst = f64::from(self.limit.burst);
+        let rate = self.rate();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+            updated.sort_unstable();
+
+            let cutoff = updated[EVICTED_BUCKETS - 1];
+            buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        } else {
+            Err(60)
+        }
+    }
+}
+
+// Whether an actor ID is on this server, going by the domain in `ServerConfig`.
+fn is_local(id: &str, domain: &str) -> bool {
+    let rest = match id.find("://") {
+        Some(index) => &id[index + "://".len()..],
+        None => return false,
+    };
+
+    rest == domain || rest.starts_with(&format!("{}/", domain))
+}
+
+fn too_many_requests(retry_after: u64) -> http_service::Response {
+    Response::builder()
+        .status(429)
+        .header("Retry-After", retry_after.to_string())
+        .body(Body::from("Too many requests"))
+        .unwrap()
+}
+
+/// Fails if a rate limit is configured for a route that doesn't exist, as it would never apply.
+pub fn check_routes(
+    limits: &HashMap<String, RouteLimits>,
+    table: &[routes::Entry],
+) -> Result<(), String> {
+    let labels: HashSet<String> = table
+        .iter()
+        .map(|entry| routes::label(&entry.route))
+        .collect();
+
+    let mut unknown: Vec<&str> = limits
+        .keys()
+        .filter(|label| !labels.contains(*label))
+        .map(String::as_str)
+        .collect();
+    unknown.sort();
+
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "rate limits for unknown routes: {}; `kroeg routes` lists them",
+            unknown.join(", ")
+        ))
+    }
+}
+
+/// Wraps a route's handler, limiting requests per client IP, per remote actor, and per local user,
+///  each with its own token bucket. Every route has its own buckets, so a client gets one per
+///  configured route label, however many paths it sends requests to under a prefix.
+///
+/// Actors and users are who the server authenticated the request as, not whatever key a signature
+///  claims to be made with, so nobody can spend someone else's budget.
+pub struct RateLimitHandler {
+    inner: Box<dyn RequestHandler>,
+    route: String,
+    domain: String,
+    per_ip: Option<Limiter>,
+    per_actor: Option<Limiter>,
+    per_user: Option<Limiter>,
+}
+
+impl RateLimitHandler {
+    pub fn new(
+        inner: Box<dyn RequestHandler>,
+        route: String,
+        limits: &RouteLimits,
+        domain: &str,
+    ) -> RateLimitHandler {
+        RateLimitHandler {
+            inner,
+            route,
+            domain: domain.to_owned(),
+            per_ip: limits.per_ip.map(Limiter::new),
+            per_actor: limits.per_actor.map(Limiter::new),
+            per_user: limits.per_user.map(Limiter::new),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for RateLimitHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        let remote = client_address(&request).map(|address| address.to_string());
+        let subject = Some(&context.user.subject).filter(|subject| *subject != "anonymous");
+        let (actor, user) = match subject {
+            Some(subject) if is_local(subject, &self.domain) => (None, Some(subject)),
+            subject => (subject, None),
+        };
+
+        let checks = [
+            (&self.per_ip, remote.as_ref()),
+            (&self.per_actor, actor),
+            (&self.per_user, user),
+        ];
+
+        for (limiter, key) in checks.iter() {
+            if let (Some(limiter), Some(key)) = (limiter, key) {
+                if let Err(retry_after) = limiter.take(key) {
+                    log::info!(
+                        "Rate limited {} on {}, retry after {}s",
+                        key,
+                        self.route,
+                        retry_after
+                    );
+                    return Ok(too_many_requests(retry_after));
+                }
+            }
+        }
+
+        self.inner.run(context, request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use async_std::task::block_on;
+    use kroeg_server::router::Route;
+    use kroeg_tap::User;
+
+    struct Nothing;
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Nothing {
+        async fn run(
+            &self,
+            _: &mut Context<'_, '_>,
+            _: http_service::Request,
+        ) -> Result<http_service::Response, ServerError> {
+            Ok(Response::new(Body::empty()))
+        }
+    }
+
+    fn limits() -> RouteLimits {
+        RouteLimits {
+            per_ip: Some(Limit {
+                burst: 1,
+                per_minute: 1,
+            }),
+            per_actor: None,
+            per_user: None,
+        }
+    }
+
+    #[test]
+    fn buckets_are_separate_per_key() {
+        let limiter = Limiter::new(limits().per_ip.unwrap());
+
+        assert!(limiter.take("192.0.2.1").is_ok());
+        assert_eq!(limiter.take("192.0.2.1"), Err(60));
+        assert!(limiter.take("192.0.2.2").is_ok());
+    }
+
+    #[test]
+    fn buckets_are_bounded() {
+        let limiter = Limiter::new(limits().per_ip.unwrap());
+        for client in 0..MAX_BUCKETS {
+            assert!(limiter.take(&client.to_string()).is_ok());
+        }
+
+        assert!(limiter.take("new").is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() <= MAX_BUCKETS);
+        assert!(buckets.contains_key("new"));
+        assert!(buckets.contains_key(&(MAX_BUCKETS - 1).to_string()));
+        assert!(!buckets.contains_key("0"));
+    }
+
+    #[test]
+    fn paths_under_a_prefix_share_buckets() {
+        let limits = RouteLimits {
+            per_ip: None,
+            per_actor: limits().per_ip,
+            per_user: None,
+        };
+        let handler = RateLimitHandler::new(
+            Box::new(Nothing),
+            "POST /*".to_owned(),
+            &limits,
+            "kroeg.example",
+        );
+
+        let post = |path: &str| {
+            let mut entity_store = MemoryStore::new();
+            let mut queue_store = MemoryStore::new();
+            let mut context = Context {
+                user: User {
+                    claims: HashMap::new(),
+                    issuer: None,
+                    subject: "https://remote.example/actor".to_owned(),
+                    audience: vec![],
+                    token_identifier: "test".to_owned(),
+                },
+
+                server_base: "https://kroeg.example".to_owned(),
+                name: "Kroeg".to_owned(),
+                description: "Kroeg".to_owned(),
+                entity_store: &mut entity_store,
+                queue_store: &mut queue_store,
+                instance_id: 1,
+            };
+
+            let request = http::Request::post(path).body(Body::empty()).unwrap();
+            block_on(handler.run(&mut context, request))
+                .unwrap()
+                .status()
+        };
+
+        assert_eq!(post("/users/a/inbox"), 200);
+        assert_eq!(post("/users/b/inbox"), 429);
+    }
+
+    #[test]
+    fn recognises_local_actors() {
+        assert!(is_local("https://kroeg.example/users/a", "kroeg.example"));
+        assert!(is_local("http://127.0.0.1:3000", "127.0.0.1:3000"));
+        assert!(!is_local(
+            "https://kroeg.example.evil/users/a",
+            "kroeg.example"
+        ));
+        assert!(!is_local(
+            "https://other.example/kroeg.example/",
+            "kroeg.example"
+        ));
+        assert!(!is_local("kroeg.example/users/a", "kroeg.example"));
+    }
+
+    #[test]
+    fn rejects_limits_for_unknown_routes() {
+        let table = vec![routes::Entry {
+            group: "core",
+            handler: "Nothing",
+            route: Route::post_prefix("/", Nothing),
+        }];
+
+        let mut limits_by_route = HashMap::new();
+        limits_by_route.insert("POST /*".to_owned(), limits());
+        assert!(check_routes(&limits_by_route, &table).is_ok());
+
+        limits_by_route.insert("POST /inbox".to_owned(), limits());
+        limits_by_route.insert("post /*".to_owned(), limits());
+        assert_eq!(
+            check_routes(&limits_by_route, &table).unwrap_err(),
+            "rate limits for unknown routes: POST /inbox, post /*; `kroeg routes` lists them"
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::config::KroegConfig;
+use crate::database::DatabasePool;
 use clap::ArgMatches;
 use http_service::Body;
 use kroeg_server::{
@@ -41,7 +42,7 @@ pub async fn handle(config: KroegConfig, matches: &ArgMatches<'_>) {
     let format = matches.value_of("format").unwrap();
     let url = matches.value_of("URL").unwrap().to_owned();
 
-    let pool = crate::DatabasePool(config.database);
+    let pool = DatabasePool::new(config.database);
     let mut conn = pool.connect().await.expect("Database connection failed");
     let (entity_store, queue_store) = conn.get();
 
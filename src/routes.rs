@@ -0,0 +1,557 @@
+use crate::admin::AdminHandler;
+use crate::caching::{self, CachingHandler};
+use crate::compression::DecompressionHandler;
+use crate::config::{DatabaseConfig, KroegConfig, RouteGroup};
+use crate::cors::Cors;
+use crate::database::DatabasePool;
+use crate::health::{self, HealthHandler, ReadyHandler};
+use crate::metrics::{Metrics, MetricsHandler};
+use crate::supervisor::WorkerStatus;
+use clap::ArgMatches;
+use futures::future::TryFutureExt;
+use http::{Response, Uri};
+use http_service::{Body, HttpService};
+use kroeg_server::{
+    context, get, nodeinfo, post, router::RequestHandler, router::Route, webfinger, KroegService,
+    ServerError,
+};
+use kroeg_tap::Context;
+
+/// Serves the JSON-LD context. It can only change with a new build, so it is serialized once and
+///  counts as modified when the process started.
+struct ContextHandler {
+    body: String,
+    last_modified: String,
+}
+
+impl ContextHandler {
+    fn new() -> ContextHandler {
+        ContextHandler {
+            body: context::read_context().to_string(),
+            last_modified: caching::http_date(chrono::Utc::now()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for ContextHandler {
+    async fn run(
+        &self,
+        _: &mut Context<'_, '_>,
+        _: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        Ok(Response::builder()
+            .status(200)
+            .header("Content-Type", "application/ld+json")
+            .header("Last-Modified", self.last_modified.as_str())
+            .body(Body::from(self.body.clone()))
+            .unwrap())
+    }
+}
+
+/// Strips the prefix a route group is mounted under from the request path, so its handlers see
+///  the paths they were written for.
+struct MountedHandler {
+    inner: Box<dyn RequestHandler>,
+    prefix: String,
+}
+
+// The URI a mounted handler sees: the same one without the prefix, if the path is the prefix or
+//  continues with a `/` after it. Otherwise `/apifoo` would count as mounted under `/api`.
+fn strip_prefix(uri: &Uri, prefix: &str) -> Option<Uri> {
+    let path = uri.path();
+    let rest = if path == prefix {
+        "/"
+    } else if path.starts_with(prefix) && path[prefix.len()..].starts_with('/') {
+        &path[prefix.len()..]
+    } else {
+        return None;
+    };
+
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest.to_owned(),
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+
+    Uri::from_parts(parts).ok()
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for MountedHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        mut request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        if let Some(uri) = strip_prefix(request.uri(), &self.prefix) {
+            *request.uri_mut() = uri;
+        }
+
+        self.inner.run(context, request).await
+    }
+}
+
+/// A route in the table, along with the group it belongs to and what handles it. Routes from
+///  other crates don't tell which handler they use, so those name where they came from instead.
+pub struct Entry {
+    pub group: &'static str,
+    pub handler: &'static str,
+    pub route: Route,
+}
+
+/// How a route shows up in metrics and logs, e.g. `GET /-/context` or `POST /*`.
+pub fn label(route: &Route) -> String {
+    format!(
+        "{} {}{}",
+        route.method,
+        route.path,
+        if route.is_prefix { "*" } else { "" }
+    )
+}
+
+/// The optional route groups, with whether they were built into this binary.
+pub fn groups(config: &KroegConfig) -> Vec<(&'static str, &RouteGroup, bool)> {
+    let routes = &config.http.routes;
+
+    vec![
+        ("mastodon", &routes.mastodon, cfg!(feature = "mastodon")),
+        ("oauth", &routes.oauth, cfg!(feature = "oauth")),
+        ("frontend", &routes.frontend, cfg!(feature = "frontend")),
+        ("webfinger", &routes.webfinger, true),
+        ("nodeinfo", &routes.nodeinfo, true),
+    ]
+}
+
+// Only builds the group's routes if it is enabled, as some groups do real work to set them up.
+fn mount(
+    group: &'static str,
+    handler: &'static str,
+    config: &RouteGroup,
+    routes: impl FnOnce() -> Vec<Route>,
+) -> Vec<Entry> {
+    if !config.enabled {
+        return vec![];
+    }
+
+    let prefix = config.prefix.trim_end_matches('/').to_owned();
+
+    routes()
+        .into_iter()
+        .map(|route| {
+            let route = if prefix.is_empty() {
+                route
+            } else {
+                Route {
+                    path: format!("{}{}", prefix, route.path),
+                    handler: Box::new(MountedHandler {
+                        inner: route.handler,
+                        prefix: prefix.clone(),
+                    }),
+                    ..route
+                }
+            };
+
+            Entry {
+                group,
+                handler,
+                route,
+            }
+        })
+        .collect()
+}
+
+fn core(handler: &'static str, route: Route) -> Entry {
+    Entry {
+        group: "core",
+        handler,
+        route,
+    }
+}
+
+/// Builds every route `serve` mounts, in the order they are registered.
+pub fn table(
+    config: &KroegConfig,
+    pool: &DatabasePool,
+    workers: &WorkerStatus,
+    metrics: &Metrics,
+) -> Vec<Entry> {
+    let groups = &config.http.routes;
+    let compression = config.http.compression.clone().unwrap_or_default();
+    let cors = config.http.cors.clone().map(Cors::new);
+    let with_cors = |route: Route| match &cors {
+        Some(cors) => cors.route(route),
+        None => route,
+    };
+
+    let mut routes = vec![
+        core(
+            "get::GetHandler",
+            with_cors(CachingHandler::route(
+                Route::get_prefix("/", get::GetHandler),
+                &config.http.cache.entities,
+            )),
+        ),
+        core(
+            "post::PostHandler",
+            with_cors(DecompressionHandler::route(
+                Route::post_prefix("/", post::PostHandler),
+                compression.max_request_size,
+            )),
+        ),
+    ];
+
+    #[cfg(feature = "frontend")]
+    routes.append(&mut mount(
+        "frontend",
+        "kroeg_frontend::routes",
+        &groups.frontend,
+        || kroeg_frontend::routes().expect("Failed to register frontend"),
+    ));
+
+    // Ensure GETs with the proper Accept get handled ActivityPub-first.
+    let activitypub = Route {
+        content_type: vec![
+            "application/activity+json".to_string(),
+            "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"".to_string(),
+        ],
+        method: http::Method::GET,
+        path: String::new(),
+        is_prefix: true,
+        handler: Box::new(get::GetHandler),
+    };
+    routes.push(core(
+        "get::GetHandler",
+        with_cors(CachingHandler::route(
+            activitypub,
+            &config.http.cache.entities,
+        )),
+    ));
+
+    routes.push(core(
+        "ContextHandler",
+        with_cors(CachingHandler::route(
+            Route::get("/-/context", ContextHandler::new()),
+            &config.http.cache.context,
+        )),
+    ));
+    routes.extend(
+        AdminHandler::routes(&config.server, pool)
+            .into_iter()
+            .map(|route| core("AdminHandler", route)),
+    );
+    routes.push(core(
+        "HealthHandler",
+        Route::get(health::HEALTH_PATH, HealthHandler(workers.clone())),
+    ));
+    routes.push(core(
+        "ReadyHandler",
+        Route::get(health::READY_PATH, ReadyHandler(pool.clone())),
+    ));
+
+    if config.http.metrics.enabled && config.http.metrics.listen.is_none() {
+        routes.push(core(
+            "MetricsHandler",
+            Route::get(
+                "/-/metrics",
+                MetricsHandler {
+                    metrics: metrics.clone(),
+                    pool: pool.clone(),
+                    workers: workers.clone(),
+                },
+            ),
+        ));
+    }
+
+    routes.append(&mut mount(
+        "nodeinfo",
+        "nodeinfo::routes",
+        &groups.nodeinfo,
+        || nodeinfo::routes().into_iter().map(&with_cors).collect(),
+    ));
+    routes.append(&mut mount(
+        "webfinger",
+        "webfinger::routes",
+        &groups.webfinger,
+        || webfinger::routes().into_iter().map(&with_cors).collect(),
+    ));
+
+    if let Some(cors) = &cors {
+        routes.push(core("PreflightHandler", cors.preflight_route()));
+    }
+
+    #[cfg(feature = "mastodon")]
+    routes.append(&mut mount(
+        "mastodon",
+        "kroeg_mastodon::routes",
+        &groups.mastodon,
+        kroeg_mastodon::routes,
+    ));
+
+    #[cfg(feature = "oauth")]
+    routes.append(&mut mount(
+        "oauth",
+        "kroeg_oauth::routes",
+        &groups.oauth,
+        kroeg_oauth::routes,
+    ));
+
+    routes
+}
+
+/// Answers with the index of its route in the table, so `--match` can tell which route the
+///  router picked.
+struct MarkerHandler(usize);
+
+const MARKER_HEADER: &str = "X-Kroeg-Route";
+
+#[async_trait::async_trait]
+impl RequestHandler for MarkerHandler {
+    async fn run(
+        &self,
+        _: &mut Context<'_, '_>,
+        _: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        Ok(Response::builder()
+            .header(MARKER_HEADER, self.0.to_string())
+            .body(Body::empty())
+            .unwrap())
+    }
+}
+
+/// Finds the route a request ends up at, by handing it to the same router `serve` uses. Every
+///  handler is replaced by a marker and the database by an empty one in memory, so nothing runs.
+async fn find_route(
+    config: &KroegConfig,
+    table: &[Entry],
+    request: http_service::Request,
+) -> Option<usize> {
+    let routes = table
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| Route {
+            content_type: entry.route.content_type.clone(),
+            method: entry.route.method.clone(),
+            path: entry.route.path.clone(),
+            is_prefix: entry.route.is_prefix,
+            handler: Box::new(MarkerHandler(index)),
+        })
+        .collect();
+
+    let pool = DatabasePool::new(DatabaseConfig::Memory);
+    let service = KroegService::new(pool, config.server.clone(), routes);
+
+    let mut connection = service.connect().into_future().await.ok()?;
+    let response = service
+        .respond(&mut connection, request)
+        .into_future()
+        .await
+        .ok()?;
+
+    response
+        .headers()
+        .get(MARKER_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+}
+
+fn print_entry(entry: &Entry) {
+    let route = &entry.route;
+
+    println!(
+        "{:<7} {:<36} {:<6} {:<10} {:<24} {}",
+        route.method,
+        if route.path.is_empty() {
+            "(any)"
+        } else {
+            route.path.as_str()
+        },
+        if route.is_prefix { "prefix" } else { "exact" },
+        entry.group,
+        entry.handler,
+        route.content_type.join(", ")
+    );
+}
+
+/// Prints the route table, or with `--match`, the route a request would end up at. Routes
+///  registered later take precedence over earlier ones, so the table is printed last to first,
+///  which is the order they are tried in.
+pub async fn handle(config: KroegConfig, matches: &ArgMatches<'_>) {
+    let pool = DatabasePool::new(config.database.clone());
+    let metrics = Metrics::new();
+    let workers = WorkerStatus::default();
+    let table = table(&config, &pool, &workers, &metrics);
+
+    if let Some(request) = matches.values_of("match") {
+        let request: Vec<&str> = request.collect();
+        let (method, path) = match request.as_slice() {
+            [method, path] => (method.to_uppercase(), *path),
+            _ => fail("--match takes a method and a path"),
+        };
+
+        let mut builder = http::Request::builder();
+        builder.method(method.as_str()).uri(path);
+        if let Some(accept) = matches.value_of("accept") {
+            builder.header("Accept", accept);
+        }
+
+        let request = match builder.body(Body::empty()) {
+            Ok(request) => request,
+            Err(e) => fail(&format!("invalid request {} {}: {}", method, path, e)),
+        };
+
+        match find_route(&config, &table, request).await {
+            Some(index) => print_entry(&table[index]),
+            None => fail(&format!("no route matches {} {}", method, path)),
+        }
+
+        return;
+    }
+
+    println!(
+        "{:<7} {:<36} {:<6} {:<10} {:<24} {}",
+        "METHOD", "PATH", "MATCH", "GROUP", "HANDLER", "CONTENT TYPES"
+    );
+
+    for entry in table.iter().rev() {
+        print_entry(entry);
+    }
+
+    for (name, group, built) in groups(&config) {
+        if !built {
+            println!("# {} is not part of this build", name);
+        } else if !group.enabled {
+            println!("# {} is disabled", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Nothing;
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Nothing {
+        async fn run(
+            &self,
+            _: &mut Context<'_, '_>,
+            _: http_service::Request,
+        ) -> Result<http_service::Response, ServerError> {
+            Ok(Response::new(Body::empty()))
+        }
+    }
+
+    fn strip(uri: &str) -> Option<String> {
+        strip_prefix(&uri.parse().unwrap(), "/api").map(|uri| uri.to_string())
+    }
+
+    fn group(enabled: bool, prefix: &str) -> RouteGroup {
+        RouteGroup {
+            enabled,
+            prefix: prefix.to_owned(),
+        }
+    }
+
+    #[test]
+    fn strips_prefixes_on_segment_boundaries() {
+        assert_eq!(
+            strip("/api/v1/timelines?limit=5"),
+            Some("/v1/timelines?limit=5".to_owned())
+        );
+        assert_eq!(strip("/api"), Some("/".to_owned()));
+        assert_eq!(strip("/api/"), Some("/".to_owned()));
+        assert_eq!(strip("/api?x=1"), Some("/?x=1".to_owned()));
+        assert_eq!(strip("/apifoo"), None);
+        assert_eq!(strip("/other/api"), None);
+    }
+
+    #[test]
+    fn mounts_groups_under_their_prefix() {
+        let routes = mount("test", "Nothing", &group(true, "/api/"), || {
+            vec![Route::get("/v1/instance", Nothing)]
+        });
+
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].route.path, "/api/v1/instance");
+
+        let routes = mount("test", "Nothing", &group(true, ""), || {
+            vec![Route::get("/v1/instance", Nothing)]
+        });
+        assert_eq!(routes[0].route.path, "/v1/instance");
+    }
+
+    #[test]
+    fn disabled_groups_are_not_built() {
+        let routes = mount("test", "Nothing", &group(false, "/api"), || {
+            panic!("disabled groups shouldn't build their routes")
+        });
+
+        assert!(routes.is_empty());
+    }
+
+    const CONFIG: &str = r#"
+        [database]
+        backend = "memory"
+
+        [server]
+        domain = "https://kroeg.example"
+        name = "Kroeg"
+        description = "Kroeg, running Kroeg"
+        instance_id = 1
+        admins = []
+    "#;
+
+    fn entry(route: Route) -> Entry {
+        Entry {
+            group: "test",
+            handler: "Nothing",
+            route,
+        }
+    }
+
+    #[test]
+    fn matches_through_the_router() {
+        let config = KroegConfig::load(CONFIG.as_bytes(), vec![], &[]).unwrap();
+        let table = vec![
+            entry(Route::get_prefix("/", Nothing)),
+            entry(Route::get("/-/context", Nothing)),
+            entry(Route {
+                content_type: vec!["application/activity+json".to_owned()],
+                method: http::Method::GET,
+                path: String::new(),
+                is_prefix: true,
+                handler: Box::new(Nothing),
+            }),
+        ];
+
+        let find = |method: &str, path: &str, accept: Option<&str>| {
+            let mut request = http::Request::builder();
+            request.method(method).uri(path);
+            if let Some(accept) = accept {
+                request.header("Accept", accept);
+            }
+
+            let request = request.body(Body::empty()).unwrap();
+            async_std::task::block_on(find_route(&config, &table, request))
+        };
+
+        assert_eq!(find("GET", "/-/context", None), Some(1));
+        assert_eq!(find("GET", "/users/a", None), Some(0));
+        assert_eq!(
+            find("GET", "/users/a", Some("application/activity+json")),
+            Some(2)
+        );
+        assert_eq!(find("POST", "/users/a", None), None);
+    }
+}
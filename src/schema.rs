@@ -0,0 +1,530 @@
+use crate::config::{ConnectionParams, KroegConfig, PostgresConfig, SslMode};
+use crate::database::DatabasePool;
+use clap::ArgMatches;
+use kroeg_tap::StoreError;
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+use postgres::params::{ConnectParams, Host};
+use postgres::tls::{Stream, TlsHandshake, TlsStream};
+use postgres::TlsMode;
+use rusqlite::OpenFlags;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+const POSTGRESQL: &[Migration] = &[Migration {
+    version: 1,
+    name: "initial",
+    sql: include_str!("../migrations/postgresql/0001_initial.sql"),
+}];
+
+const SQLITE: &[Migration] = &[Migration {
+    version: 1,
+    name: "initial",
+    sql: include_str!("../migrations/sqlite/0001_initial.sql"),
+}];
+
+const CREATE_MIGRATIONS_TABLE: &str = "create table if not exists schema_migrations (
+    version bigint primary key not null,
+    name text not null,
+    applied_at text not null default current_timestamp
+)";
+
+/// Direct access to a database's schema, bypassing the entity and queue stores.
+pub trait Schema {
+    fn migrations(&self) -> &'static [Migration];
+
+    /// Returns the versions that have been applied. This only reads, so a database without a
+    ///  migrations table has none applied.
+    fn applied(&mut self) -> Result<HashSet<i64>, StoreError>;
+
+    /// Runs a migration and records it, all in one transaction. The migrations table is created
+    ///  by the first one.
+    fn apply(&mut self, migration: &Migration) -> Result<(), StoreError>;
+}
+
+/// Cellar connections only offer the entity and queue stores, so migrations run over a connection
+///  of their own, opened from the pool's config.
+pub struct PostgresSchema(postgres::Connection);
+
+/// Splits a `host:port` pair, where the host may be a bracketed IPv6 address like `[::1]:5432`.
+///  Without a port, the whole string is the host, so a bare `::1` works too.
+fn split_server(server: &str) -> Result<(&str, u16), StoreError> {
+    let (host, port) = if server.starts_with('[') {
+        match server.find(']') {
+            Some(end) if end + 1 == server.len() => (&server[1..end], None),
+            Some(end) if server[end + 1..].starts_with(':') => {
+                (&server[1..end], Some(&server[end + 2..]))
+            }
+            _ => return Err(format!("invalid database server {:?}", server).into()),
+        }
+    } else {
+        match server.rfind(':') {
+            Some(index) if server[..index].find(':').is_none() => {
+                (&server[..index], Some(&server[index + 1..]))
+            }
+            _ => (server, None),
+        }
+    };
+
+    match port {
+        Some(port) => Ok((host, port.parse()?)),
+        None => Ok((host, 5432)),
+    }
+}
+
+// Postgres' TLS streams need to be our own type to implement its traits.
+#[derive(Debug)]
+struct PostgresTlsStream(SslStream<Stream>);
+
+impl Read for PostgresTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for PostgresTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl TlsStream for PostgresTlsStream {
+    fn get_ref(&self) -> &Stream {
+        self.0.get_ref()
+    }
+
+    fn get_mut(&mut self) -> &mut Stream {
+        self.0.get_mut()
+    }
+}
+
+/// Does the TLS handshake for PostgreSQL connections with the openssl crate kroeg already uses.
+#[derive(Debug)]
+struct PostgresTls {
+    connector: SslConnector,
+    verify_hostname: bool,
+}
+
+impl PostgresTls {
+    fn new(params: &ConnectionParams) -> Result<Option<PostgresTls>, StoreError> {
+        let verify = match params.sslmode {
+            SslMode::Disable => return Ok(None),
+            SslMode::Prefer => false,
+            SslMode::Require => params.sslrootcert.is_some(),
+            SslMode::VerifyCa | SslMode::VerifyFull => true,
+        };
+
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if let Some(sslrootcert) = &params.sslrootcert {
+            builder
+                .set_ca_file(sslrootcert)
+                .map_err(|e| format!("failed to read {}: {}", sslrootcert, e))?;
+        }
+
+        if !verify {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+
+        Ok(Some(PostgresTls {
+            connector: builder.build(),
+            verify_hostname: params.sslmode == SslMode::VerifyFull,
+        }))
+    }
+}
+
+impl TlsHandshake for PostgresTls {
+    fn tls_handshake(
+        &self,
+        host: &str,
+        stream: Stream,
+    ) -> Result<Box<dyn TlsStream>, Box<dyn Error + Send + Sync>> {
+        let mut config = self.connector.configure()?;
+        config.set_verify_hostname(self.verify_hostname);
+
+        let stream = config.connect(host, stream).map_err(|e| e.to_string())?;
+
+        Ok(Box::new(PostgresTlsStream(stream)))
+    }
+}
+
+/// Opens a blocking connection with the same parameters as the pool, using TLS as its sslmode
+///  asks. The pool's `acquire_timeout` bounds the connection attempt, like it bounds leasing from
+///  the pool.
+pub fn connect_postgres(config: &PostgresConfig) -> Result<postgres::Connection, StoreError> {
+    let params = config.connection_params()?;
+    let (host, port) = split_server(&params.server)?;
+    let tls = PostgresTls::new(&params)?;
+
+    let connect_params = ConnectParams::builder()
+        .port(port)
+        .user(&params.username, Some(&params.password))
+        .database(&params.database)
+        .connect_timeout(Some(Duration::from_secs(config.pool.acquire_timeout)))
+        .build(Host::Tcp(host.to_owned()));
+
+    let mode = match &tls {
+        None => TlsMode::None,
+        Some(tls) if params.sslmode.requires_tls() => TlsMode::Require(tls),
+        Some(tls) => TlsMode::Prefer(tls),
+    };
+
+    Ok(postgres::Connection::connect(connect_params, mode)?)
+}
+
+impl PostgresSchema {
+    pub fn connect(config: &PostgresConfig) -> Result<PostgresSchema, StoreError> {
+        Ok(PostgresSchema(connect_postgres(config)?))
+    }
+}
+
+impl Schema for PostgresSchema {
+    fn migrations(&self) -> &'static [Migration] {
+        POSTGRESQL
+    }
+
+    fn applied(&mut self) -> Result<HashSet<i64>, StoreError> {
+        let rows = self
+            .0
+            .query("select to_regclass('schema_migrations') is not null", &[])?;
+        if !rows.get(0).get::<_, bool>(0) {
+            return Ok(HashSet::new());
+        }
+
+        let rows = self.0.query("select version from schema_migrations", &[])?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn apply(&mut self, migration: &Migration) -> Result<(), StoreError> {
+        let transaction = self.0.transaction()?;
+        transaction.batch_execute(CREATE_MIGRATIONS_TABLE)?;
+        transaction.batch_execute(migration.sql)?;
+        transaction.execute(
+            "insert into schema_migrations (version, name) values ($1, $2)",
+            &[&migration.version, &migration.name],
+        )?;
+
+        Ok(transaction.commit()?)
+    }
+}
+
+pub struct SqliteSchema(rusqlite::Connection);
+
+impl SqliteSchema {
+    pub fn open(path: &str) -> Result<SqliteSchema, StoreError> {
+        Ok(SqliteSchema(rusqlite::Connection::open(path)?))
+    }
+
+    /// Opens an existing database without creating the file, and fails on any write.
+    pub fn open_read_only(path: &str) -> Result<SqliteSchema, StoreError> {
+        if !Path::new(path).exists() {
+            return Err(format!("{} does not exist, run `db init` to create it", path).into());
+        }
+
+        Ok(SqliteSchema(rusqlite::Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?))
+    }
+}
+
+impl Schema for SqliteSchema {
+    fn migrations(&self) -> &'static [Migration] {
+        SQLITE
+    }
+
+    fn applied(&mut self) -> Result<HashSet<i64>, StoreError> {
+        let exists: bool = self.0.query_row(
+            "select count(*) > 0 from sqlite_master where type = 'table' and name = 'schema_migrations'",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(HashSet::new());
+        }
+
+        let mut statement = self.0.prepare("select version from schema_migrations")?;
+        let versions = statement.query_map(rusqlite::NO_PARAMS, |row| row.get(0))?;
+
+        Ok(versions.collect::<Result<_, _>>()?)
+    }
+
+    fn apply(&mut self, migration: &Migration) -> Result<(), StoreError> {
+        let transaction = self.0.transaction()?;
+        transaction.execute_batch(CREATE_MIGRATIONS_TABLE)?;
+        transaction.execute_batch(migration.sql)?;
+        transaction.execute(
+            "insert into schema_migrations (version, name) values (?1, ?2)",
+            rusqlite::params![migration.version, migration.name],
+        )?;
+
+        Ok(transaction.commit()?)
+    }
+}
+
+/// Returns the migrations that haven't been applied yet, in the order they should run in.
+pub fn pending(schema: &mut dyn Schema) -> Result<Vec<&'static Migration>, StoreError> {
+    let applied = schema.applied()?;
+
+    Ok(schema
+        .migrations()
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+fn migrate(schema: &mut dyn Schema) -> Result<(), StoreError> {
+    let pending = pending(schema)?;
+    if pending.is_empty() {
+        println!("Schema is up to date");
+    }
+
+    for migration in pending {
+        println!("Applying {:04} {}", migration.version, migration.name);
+        schema.apply(migration)?;
+    }
+
+    Ok(())
+}
+
+fn init(schema: &mut dyn Schema) -> Result<(), StoreError> {
+    if !schema.applied()?.is_empty() {
+        return Err("database is already initialized, use `db migrate` to update it".into());
+    }
+
+    migrate(schema)
+}
+
+fn status(schema: &mut dyn Schema) -> Result<(), StoreError> {
+    let applied = schema.applied()?;
+
+    for migration in schema.migrations() {
+        let state = if applied.contains(&migration.version) {
+            "applied"
+        } else {
+            "pending"
+        };
+
+        println!("{:04} {:<24} {}", migration.version, migration.name, state);
+    }
+
+    Ok(())
+}
+
+/// Fails if the database has migrations left to apply. Backends without a schema always pass.
+pub fn ensure_current(pool: &DatabasePool) -> Result<(), StoreError> {
+    let mut schema = match pool.schema(true)? {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+
+    let pending = pending(&mut *schema)?;
+    if !pending.is_empty() {
+        return Err(format!(
+            "database schema is out of date, {} migration(s) pending; run `db migrate`",
+            pending.len()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+pub async fn handle(config: KroegConfig, matches: &ArgMatches<'_>) {
+    let pool = DatabasePool::new(config.database);
+    let read_only = matches.subcommand_name() == Some("status");
+
+    let mut schema = match pool.schema(read_only) {
+        Ok(Some(schema)) => schema,
+        Ok(None) => {
+            println!("This database backend has no schema to manage");
+            return;
+        }
+
+        Err(e) => {
+            eprintln!("error: failed to connect to the database: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match matches.subcommand() {
+        ("init", _) => init(&mut *schema),
+        ("migrate", _) => migrate(&mut *schema),
+        ("status", _) => status(&mut *schema),
+        _ => unreachable!(),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("kroeg-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn schema(name: &str) -> SqliteSchema {
+        SqliteSchema::open(&database(name)).unwrap()
+    }
+
+    fn tables(schema: &SqliteSchema) -> i64 {
+        schema
+            .0
+            .query_row(
+                "select count(*) from sqlite_master where type = 'table'",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn splits_servers() {
+        assert_eq!(split_server("localhost").unwrap(), ("localhost", 5432));
+        assert_eq!(split_server("db:6432").unwrap(), ("db", 6432));
+        assert_eq!(split_server("[::1]:6432").unwrap(), ("::1", 6432));
+        assert_eq!(split_server("[::1]").unwrap(), ("::1", 5432));
+        assert_eq!(split_server("::1").unwrap(), ("::1", 5432));
+        assert!(split_server("[::1").is_err());
+        assert!(split_server("db:port").is_err());
+    }
+
+    fn params(sslmode: SslMode, sslrootcert: Option<&str>) -> ConnectionParams {
+        ConnectionParams {
+            server: "localhost:5432".to_owned(),
+            username: "kroeg".to_owned(),
+            password: String::new(),
+            database: "kroeg".to_owned(),
+            sslmode,
+            sslrootcert: sslrootcert.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn sslmode_picks_the_handshake() {
+        assert!(PostgresTls::new(&params(SslMode::Disable, None))
+            .unwrap()
+            .is_none());
+
+        let full = PostgresTls::new(&params(SslMode::VerifyFull, None))
+            .unwrap()
+            .unwrap();
+        assert!(full.verify_hostname);
+
+        let ca = PostgresTls::new(&params(SslMode::VerifyCa, None))
+            .unwrap()
+            .unwrap();
+        assert!(!ca.verify_hostname);
+
+        assert!(PostgresTls::new(&params(SslMode::Require, Some("/nonexistent/ca.pem"))).is_err());
+    }
+
+    #[test]
+    fn status_does_not_write() {
+        let path = database("schema-status");
+        assert!(SqliteSchema::open_read_only(&path).is_err());
+        assert!(!Path::new(&path).exists());
+
+        rusqlite::Connection::open(&path).unwrap();
+        let mut schema = SqliteSchema::open_read_only(&path).unwrap();
+
+        assert_eq!(pending(&mut schema).unwrap().len(), SQLITE.len());
+        assert!(schema.apply(&SQLITE[0]).is_err());
+        assert_eq!(tables(&schema), 0);
+    }
+
+    #[test]
+    fn migrates_once() {
+        let mut schema = schema("schema-migrate");
+
+        init(&mut schema).unwrap();
+        assert!(pending(&mut schema).unwrap().is_empty());
+        assert!(tables(&schema) > 0);
+
+        assert!(init(&mut schema).is_err());
+        migrate(&mut schema).unwrap();
+    }
+
+    // Checks the hand-written PostgreSQL migration against the queries cellar really runs. It needs
+    //  a scratch database, whose kroeg tables it drops first:
+    //  `KROEG_TEST_POSTGRES=postgres://postgres@localhost/scratch cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn cellar_works_on_the_migrated_schema() {
+        use crate::config::DatabaseConfig;
+        use kroeg_server::{LeasedConnection, StorePool};
+        use kroeg_tap::StoreItem;
+        use serde_json::json;
+
+        let url = std::env::var("KROEG_TEST_POSTGRES").expect("KROEG_TEST_POSTGRES is not set");
+        let config: PostgresConfig = toml::from_str(&format!("url = {:?}", url)).unwrap();
+
+        connect_postgres(&config)
+            .unwrap()
+            .batch_execute(
+                "drop table if exists schema_migrations, queue_item, collection_item, quad, \
+                 attribute cascade",
+            )
+            .unwrap();
+        init(&mut PostgresSchema::connect(&config).unwrap()).unwrap();
+
+        let pool = DatabasePool::new(DatabaseConfig::PostgreSQL(config));
+        async_std::task::block_on(async {
+            let mut connection = pool.connect().await.unwrap();
+            let (store, queue) = connection.get();
+
+            let id = "https://example.com/notes/1".to_owned();
+            let mut note = StoreItem::parse(
+                &id,
+                &json!([{
+                    "@id": id,
+                    "@type": ["https://www.w3.org/ns/activitystreams#Note"],
+                    "https://www.w3.org/ns/activitystreams#content": [{ "@value": "hi" }],
+                }]),
+            )
+            .unwrap();
+            store.put(id.to_owned(), &mut note).await.unwrap();
+            assert_eq!(
+                store.get(id.to_owned(), true).await.unwrap().unwrap().id(),
+                id
+            );
+
+            let outbox = "https://example.com/outbox".to_owned();
+            store
+                .insert_collection(outbox.to_owned(), id.to_owned())
+                .await
+                .unwrap();
+            let page = store
+                .read_collection(outbox.to_owned(), None, None)
+                .await
+                .unwrap();
+            assert_eq!(page.items, vec![id.to_owned()]);
+
+            queue
+                .add("deliver".to_owned(), "{}".to_owned())
+                .await
+                .unwrap();
+            let item = queue.get_item().await.unwrap().unwrap();
+            assert_eq!(item.event(), "deliver");
+        });
+    }
+}
@@ -0,0 +1,412 @@
+use crate::access_log::{self, AccessLog};
+use crate::cors::ErrorHeaders;
+use crate::database::DatabasePool;
+use crate::health;
+use crate::supervisor::WorkerStatus;
+use futures::future::{self, BoxFuture, FutureExt, FutureObj, TryFuture, TryFutureExt};
+use futures::task::{Spawn, SpawnError};
+use http_service::{HttpService, Request, Response};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+type ResponseError<S> = <<S as HttpService>::ResponseFuture as TryFuture>::Error;
+
+/// The address of the client that sent a request, as resolved by `FrontService`.
+#[derive(Clone, Copy)]
+struct ClientAddress(Option<IpAddr>);
+
+/// The address of the client that sent a request. This is `None` for requests that came in over a
+///  Unix socket without saying who they were forwarded for.
+pub fn client_address(request: &Request) -> Option<IpAddr> {
+    request
+        .extensions()
+        .get::<ClientAddress>()
+        .and_then(|address| address.0)
+}
+
+/// Sits in front of `KroegService`, which leases a database connection for every request before
+///  routing it. Requests that must not depend on the database are answered here instead.
+///
+/// Every connection is served by its own copy, which knows the peer it talks to. If that peer is a
+///  trusted proxy, the client is whoever it says it forwarded the request for.
+pub struct FrontService<S> {
+    inner: Arc<S>,
+    pool: DatabasePool,
+    workers: WorkerStatus,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+    access_log: Option<AccessLog>,
+    peer: Option<SocketAddr>,
+}
+
+impl<S> FrontService<S> {
+    pub fn new(
+        inner: S,
+        pool: DatabasePool,
+        workers: WorkerStatus,
+        trusted_proxies: Vec<IpAddr>,
+    ) -> FrontService<S> {
+        FrontService {
+            inner: Arc::new(inner),
+            pool,
+            workers,
+            trusted_proxies: Arc::new(trusted_proxies),
+            access_log: None,
+            peer: None,
+        }
+    }
+
+    /// Writes every request to `access_log` once it has been answered.
+    pub fn with_access_log(self, access_log: AccessLog) -> FrontService<S> {
+        FrontService {
+            access_log: Some(access_log),
+            ..self
+        }
+    }
+
+    /// A copy for a connection from `peer`, which is `None` for Unix sockets.
+    pub fn for_peer(&self, peer: Option<SocketAddr>) -> FrontService<S> {
+        FrontService {
+            inner: self.inner.clone(),
+            pool: self.pool.clone(),
+            workers: self.workers.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+            access_log: self.access_log.clone(),
+            peer,
+        }
+    }
+
+    // Only something local can connect over a Unix socket, which is taken to be a proxy.
+    fn trusted(&self, address: Option<IpAddr>) -> bool {
+        address.map_or(true, |address| self.trusted_proxies.contains(&address))
+    }
+
+    // Follows X-Forwarded-For from the right, past every proxy that is trusted. The first address
+    //  that isn't trusted is the client, as anything left of it could have been made up.
+    fn resolve_client(&self, request: &Request) -> Option<IpAddr> {
+        let mut client = self.peer.map(|peer| peer.ip());
+        if !self.trusted(client) {
+            return client;
+        }
+
+        let mut forwarded: Vec<&str> = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        if forwarded.is_empty() {
+            forwarded.extend(
+                request
+                    .headers()
+                    .get("X-Real-IP")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::trim),
+            );
+        }
+
+        for address in forwarded.into_iter().rev() {
+            match address.parse() {
+                Ok(address) => client = Some(address),
+                Err(_) => break,
+            }
+
+            if !self.trusted(client) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+impl<S: HttpService> FrontService<S>
+where
+    ResponseError<S>: Send + 'static,
+{
+    fn answer(
+        &self,
+        connection: &mut S::Connection,
+        mut request: Request,
+    ) -> BoxFuture<'static, Result<Response, ResponseError<S>>> {
+        if request.method() == http::Method::GET {
+            // Liveness probes keep working while the database is down or the pool is exhausted.
+            if request.uri().path() == health::HEALTH_PATH {
+                access_log::set_route(&request, &format!("GET {}", health::HEALTH_PATH));
+                return future::ok(health::health(&self.workers)).boxed();
+            }
+
+            // Readiness probes lease their own connection, instead of waiting on the one
+            //  `KroegService` would lease for them.
+            if request.uri().path() == health::READY_PATH {
+                access_log::set_route(&request, &format!("GET {}", health::READY_PATH));
+                return health::ready(self.pool.clone()).map(Ok).boxed();
+            }
+        }
+
+        // A route with CORS that fails leaves its headers for the response rendered from the error.
+        let error_headers = ErrorHeaders::default();
+        request.extensions_mut().insert(error_headers.clone());
+
+        self.inner
+            .respond(connection, request)
+            .into_future()
+            .map_ok(move |mut response| {
+                error_headers.apply(&mut response);
+                response
+            })
+            .boxed()
+    }
+}
+
+/// Runs the tasks hyper spawns for a connection on async-std.
+#[derive(Clone, Copy)]
+pub struct TaskSpawner;
+
+impl Spawn for &TaskSpawner {
+    fn spawn_obj(&mut self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        async_std::task::spawn(future);
+        Ok(())
+    }
+}
+
+impl<S: HttpService> HttpService for FrontService<S>
+where
+    ResponseError<S>: Send + 'static,
+{
+    type Connection = S::Connection;
+    type ConnectionFuture = S::ConnectionFuture;
+    type ResponseFuture = BoxFuture<'static, Result<Response, ResponseError<S>>>;
+
+    fn connect(&self) -> Self::ConnectionFuture {
+        self.inner.connect()
+    }
+
+    fn respond(
+        &self,
+        connection: &mut S::Connection,
+        mut request: Request,
+    ) -> Self::ResponseFuture {
+        let client = self.resolve_client(&request);
+        request.extensions_mut().insert(ClientAddress(client));
+
+        // Every request is logged with the status it was really answered with, including the ones
+        //  that no route matched or that are answered right here.
+        let entry = self
+            .access_log
+            .as_ref()
+            .map(|access_log| access_log.start(&mut request));
+
+        let response = self.answer(connection, request);
+
+        match entry {
+            Some(entry) => response
+                .map(|response| match response {
+                    Ok(response) => Ok(entry.finish(response)),
+                    Err(e) => {
+                        entry.fail();
+                        Err(e)
+                    }
+                })
+                .boxed(),
+
+            None => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AccessLogConfig, AccessLogFormat, DatabaseConfig};
+    use async_std::task::block_on;
+    use futures::future::Ready;
+    use http_service::Body;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Counts the requests that reach it, like `KroegService` would lease a connection for each.
+    struct Counting(Arc<AtomicUsize>);
+
+    impl HttpService for Counting {
+        type Connection = ();
+        type ConnectionFuture = Ready<Result<(), io::Error>>;
+        type ResponseFuture = Ready<Result<Response, io::Error>>;
+
+        fn connect(&self) -> Self::ConnectionFuture {
+            future::ok(())
+        }
+
+        fn respond(&self, _: &mut (), _: Request) -> Self::ResponseFuture {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            future::ok(http::Response::new(Body::empty()))
+        }
+    }
+
+    fn get(service: &FrontService<Counting>, path: &str) -> Response {
+        let request = http::Request::get(path).body(Body::empty()).unwrap();
+        block_on(service.respond(&mut (), request)).unwrap()
+    }
+
+    #[test]
+    fn answers_health_checks_itself() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let service = FrontService::new(
+            Counting(requests.clone()),
+            DatabasePool::new(DatabaseConfig::Memory),
+            WorkerStatus::default(),
+            Vec::new(),
+        );
+
+        assert_eq!(get(&service, "/-/health").status(), 200);
+        assert_eq!(get(&service, "/-/ready").status(), 200);
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+        get(&service, "/-/health/extra");
+        get(&service, "/-/ready/extra");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    // Answers like `KroegService` does when a route with CORS failed with a 404.
+    struct NotFound;
+
+    impl HttpService for NotFound {
+        type Connection = ();
+        type ConnectionFuture = Ready<Result<(), io::Error>>;
+        type ResponseFuture = Ready<Result<Response, io::Error>>;
+
+        fn connect(&self) -> Self::ConnectionFuture {
+            future::ok(())
+        }
+
+        fn respond(&self, _: &mut (), request: Request) -> Self::ResponseFuture {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(
+                "Access-Control-Allow-Origin",
+                http::header::HeaderValue::from_static("*"),
+            );
+            request
+                .extensions()
+                .get::<ErrorHeaders>()
+                .unwrap()
+                .set(headers);
+
+            future::ok(
+                http::Response::builder()
+                    .status(404)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        }
+    }
+
+    #[test]
+    fn adds_cors_headers_to_errors() {
+        let service = FrontService::new(
+            NotFound,
+            DatabasePool::new(DatabaseConfig::Memory),
+            WorkerStatus::default(),
+            Vec::new(),
+        );
+
+        let request = http::Request::get("/missing").body(Body::empty()).unwrap();
+        let response = block_on(service.respond(&mut (), request)).unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], "*");
+    }
+
+    #[test]
+    fn logs_every_request_with_its_status() {
+        let path = std::env::temp_dir().join(format!("kroeg-front-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let access_log = AccessLog::open(&AccessLogConfig {
+            format: AccessLogFormat::Json,
+            path: Some(path.to_str().unwrap().to_owned()),
+        })
+        .unwrap();
+        let service = FrontService::new(
+            NotFound,
+            DatabasePool::new(DatabaseConfig::Memory),
+            WorkerStatus::default(),
+            Vec::new(),
+        )
+        .with_access_log(access_log);
+
+        for target in &["/missing", "/-/health"] {
+            let request = http::Request::get(*target).body(Body::empty()).unwrap();
+            let response = block_on(service.respond(&mut (), request)).unwrap();
+            block_on(response.into_body().into_vec()).unwrap();
+        }
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["path"], "/missing");
+        assert_eq!(lines[0]["status"], 404);
+        assert_eq!(lines[0]["route"], serde_json::Value::Null);
+        assert_eq!(lines[1]["status"], 200);
+        assert_eq!(lines[1]["route"], "GET /-/health");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn client(peer: Option<&str>, headers: &[(&str, &str)]) -> Option<IpAddr> {
+        let service = FrontService::new(
+            Counting(Arc::new(AtomicUsize::new(0))),
+            DatabasePool::new(DatabaseConfig::Memory),
+            WorkerStatus::default(),
+            vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+        );
+        let service = service.for_peer(peer.map(|peer| peer.parse().unwrap()));
+
+        let mut request = http::Request::get("/").body(Body::empty()).unwrap();
+        for (name, value) in headers {
+            request
+                .headers_mut()
+                .append(*name, http::header::HeaderValue::from_str(value).unwrap());
+        }
+
+        service.resolve_client(&request)
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn only_trusts_forwarding_from_proxies() {
+        let forwarded = [("X-Forwarded-For", "192.0.2.1")];
+        assert_eq!(
+            client(Some("198.51.100.1:1234"), &forwarded),
+            ip("198.51.100.1")
+        );
+        assert_eq!(client(Some("10.0.0.1:1234"), &forwarded), ip("192.0.2.1"));
+        assert_eq!(client(None, &forwarded), ip("192.0.2.1"));
+        assert_eq!(client(None, &[("X-Real-IP", "192.0.2.2")]), ip("192.0.2.2"));
+        assert_eq!(client(None, &[]), None);
+    }
+
+    #[test]
+    fn skips_trusted_proxies_in_the_chain() {
+        let peer = Some("10.0.0.1:1234");
+
+        let chain = [("X-Forwarded-For", "203.0.113.9, 192.0.2.1, 10.0.0.2")];
+        assert_eq!(client(peer, &chain), ip("192.0.2.1"));
+
+        let split = [
+            ("X-Forwarded-For", "203.0.113.9"),
+            ("X-Forwarded-For", "192.0.2.1"),
+        ];
+        assert_eq!(client(peer, &split), ip("192.0.2.1"));
+
+        let garbage = [("X-Forwarded-For", "nonsense, 10.0.0.2")];
+        assert_eq!(client(peer, &garbage), ip("10.0.0.2"));
+    }
+}
@@ -0,0 +1,394 @@
+use crate::database::DatabasePool;
+use async_std::task::TaskId;
+use futures::future::{AbortHandle, Abortable, Aborted};
+use http::Response;
+use http_service::Body;
+use kroeg_server::{router::RequestHandler, LeasedConnection, ServerError, StorePool};
+use kroeg_tap::{Context, QueueItem, QueueStore, StoreError};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Identifies a queue item in progress: the task working on it, and where the item lives. Both
+///  together are unique while the item exists, even if two jobs have the same event and data.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct JobId {
+    task: TaskId,
+    item: usize,
+}
+
+impl JobId {
+    fn of(item: &(dyn QueueItem + Send)) -> JobId {
+        JobId {
+            task: async_std::task::current().id(),
+            item: item as *const _ as *const () as usize,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    stopping: AtomicBool,
+    requests: AtomicUsize,
+    jobs: Mutex<HashMap<JobId, (String, String)>>,
+
+    /// Jobs whose task stopped before marking them, because it panicked or was cancelled.
+    abandoned: Mutex<Vec<(String, String)>>,
+
+    /// Cancels the delivery workers, keyed by their worker ID.
+    workers: Mutex<HashMap<usize, AbortHandle>>,
+}
+
+/// Coordinates a graceful shutdown: once triggered, no new requests or queue items are taken on,
+///  and the ones already in progress are given some time to finish.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<ShutdownState>);
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn trigger(&self) {
+        self.0.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.0.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Triggers the shutdown on SIGINT or SIGTERM. A second signal exits immediately. SIGHUP is
+    ///  left alone, as it reloads certificates and reopens the access log.
+    pub fn listen_for_signals(&self) {
+        let shutdown = self.clone();
+        let signals = Signals::new(&[signal_hook::SIGINT, signal_hook::SIGTERM])
+            .expect("Failed to install signal handler");
+
+        thread::Builder::new()
+            .name("signals".to_owned())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    if shutdown.is_stopping() {
+                        std::process::exit(1);
+                    }
+
+                    log::info!("Shutting down, send the signal again to exit immediately");
+                    shutdown.trigger();
+                }
+            })
+            .expect("Failed to install signal handler");
+    }
+
+    /// Resolves once the shutdown has been triggered.
+    pub async fn triggered(&self) {
+        while !self.is_stopping() {
+            async_std::task::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Waits until all in-flight requests and queue items are done, or the timeout passes.
+    ///  Returns whether everything finished in time.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+
+        loop {
+            let requests = self.0.requests.load(Ordering::SeqCst);
+            let jobs = self.0.jobs.lock().unwrap().len();
+
+            if requests == 0 && jobs == 0 {
+                return true;
+            }
+
+            if start.elapsed() >= timeout {
+                return false;
+            }
+
+            async_std::task::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Runs a delivery worker so that `cancel_workers` can stop it. Whatever job it was working
+    ///  on when it stops without marking it, by a panic or by being cancelled, is kept to be
+    ///  requeued.
+    pub async fn run_worker<F: Future>(&self, id: usize, worker: F) -> Result<F::Output, Aborted> {
+        let (handle, registration) = AbortHandle::new_pair();
+        self.0.workers.lock().unwrap().insert(id, handle);
+
+        let _guard = WorkerGuard {
+            shutdown: self.clone(),
+            id,
+            task: async_std::task::current().id(),
+        };
+
+        Abortable::new(worker, registration).await
+    }
+
+    /// Stops all delivery workers at their next await, and waits until they have.
+    pub async fn cancel_workers(&self) {
+        for handle in self.0.workers.lock().unwrap().values() {
+            handle.abort();
+        }
+
+        while !self.0.workers.lock().unwrap().is_empty() {
+            async_std::task::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Takes the jobs that were abandoned by their worker, along with any that are still in
+    ///  progress. Once the workers are cancelled, nothing is working on either.
+    pub fn take_unfinished(&self) -> Vec<(String, String)> {
+        let mut unfinished: Vec<_> = self.0.abandoned.lock().unwrap().drain(..).collect();
+        if self.is_stopping() {
+            unfinished.extend(self.0.jobs.lock().unwrap().drain().map(|(_, job)| job));
+        }
+
+        unfinished
+    }
+
+    fn start_job(&self, item: &(dyn QueueItem + Send)) {
+        let job = (item.event().to_owned(), item.data().to_owned());
+        self.0.jobs.lock().unwrap().insert(JobId::of(item), job);
+    }
+
+    fn finish_job(&self, id: JobId) {
+        self.0.jobs.lock().unwrap().remove(&id);
+    }
+}
+
+// Dropped when a worker stops for any reason, including a panic unwinding through it.
+struct WorkerGuard {
+    shutdown: Shutdown,
+    id: usize,
+    task: TaskId,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        let state = &(self.shutdown).0;
+
+        let mut jobs = state.jobs.lock().unwrap();
+        let ids: Vec<JobId> = jobs
+            .keys()
+            .filter(|id| id.task == self.task)
+            .cloned()
+            .collect();
+
+        let mut abandoned = state.abandoned.lock().unwrap();
+        for id in ids {
+            abandoned.extend(jobs.remove(&id));
+        }
+
+        state.workers.lock().unwrap().remove(&self.id);
+    }
+}
+
+struct RequestGuard(Shutdown);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        (self.0).0.requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Wraps a route's handler, refusing new requests once shutting down and keeping track of the
+///  ones still in progress.
+pub struct DrainingHandler {
+    inner: Box<dyn RequestHandler>,
+    shutdown: Shutdown,
+}
+
+impl DrainingHandler {
+    pub fn new(inner: Box<dyn RequestHandler>, shutdown: Shutdown) -> DrainingHandler {
+        DrainingHandler { inner, shutdown }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for DrainingHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        if self.shutdown.is_stopping() {
+            return Ok(Response::builder()
+                .status(503)
+                .header("Connection", "close")
+                .header("Retry-After", "5")
+                .body(Body::empty())
+                .unwrap());
+        }
+
+        self.shutdown.0.requests.fetch_add(1, Ordering::SeqCst);
+        let _guard = RequestGuard(self.shutdown.clone());
+
+        self.inner.run(context, request).await
+    }
+}
+
+/// Wraps a queue store, so it stops handing out items once shutting down and remembers which
+///  items are still being worked on.
+pub struct ShutdownQueue<Q> {
+    inner: Q,
+    shutdown: Shutdown,
+}
+
+impl<Q> ShutdownQueue<Q> {
+    pub fn new(inner: Q, shutdown: Shutdown) -> ShutdownQueue<Q> {
+        ShutdownQueue { inner, shutdown }
+    }
+}
+
+#[async_trait::async_trait]
+impl<Q: QueueStore> QueueStore for ShutdownQueue<Q> {
+    async fn get_item(&mut self) -> Result<Option<Box<dyn QueueItem + Send>>, StoreError> {
+        if self.shutdown.is_stopping() {
+            return Ok(None);
+        }
+
+        let item = self.inner.get_item().await?;
+        if let Some(item) = &item {
+            self.shutdown.start_job(&**item);
+        }
+
+        Ok(item)
+    }
+
+    // The job only counts as finished once the inner store is done with it, so a failed item is
+    //  safely requeued before the shutdown can complete.
+    async fn mark_success(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        let id = JobId::of(&*item);
+        let result = self.inner.mark_success(item).await;
+        self.shutdown.finish_job(id);
+
+        result
+    }
+
+    async fn mark_failure(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        let id = JobId::of(&*item);
+        let result = self.inner.mark_failure(item).await;
+        self.shutdown.finish_job(id);
+
+        result
+    }
+
+    async fn add(&mut self, event: String, data: String) -> Result<(), StoreError> {
+        self.inner.add(event, data).await
+    }
+}
+
+/// Puts queue items that were still in progress back into the queue.
+pub async fn requeue(pool: &DatabasePool, jobs: Vec<(String, String)>) -> Result<(), StoreError> {
+    if jobs.is_empty() {
+        return Ok(());
+    }
+
+    let mut conn = pool.connect().await?;
+    let (_, queue) = conn.get();
+
+    for (event, data) in jobs {
+        queue.add(event, data).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use async_std::task;
+    use futures::future::FutureExt;
+    use std::panic::AssertUnwindSafe;
+
+    fn queue(shutdown: &Shutdown, jobs: usize) -> ShutdownQueue<MemoryStore> {
+        let mut queue = ShutdownQueue::new(MemoryStore::new(), shutdown.clone());
+
+        task::block_on(async {
+            for _ in 0..jobs {
+                queue
+                    .add("deliver".to_owned(), "https://example.com/inbox".to_owned())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        queue
+    }
+
+    #[test]
+    fn identical_jobs_are_tracked_separately() {
+        let shutdown = Shutdown::new();
+        let mut queue = queue(&shutdown, 2);
+
+        task::block_on(task::spawn(async move {
+            let first = queue.get_item().await.unwrap().unwrap();
+            let second = queue.get_item().await.unwrap().unwrap();
+
+            queue.mark_success(first).await.unwrap();
+            assert!(!shutdown.drain(Duration::from_millis(0)).await);
+
+            queue.mark_success(second).await.unwrap();
+            assert!(shutdown.drain(Duration::from_millis(0)).await);
+        }));
+    }
+
+    #[test]
+    fn panicking_workers_abandon_their_job() {
+        let shutdown = Shutdown::new();
+        let mut queue = queue(&shutdown, 1);
+
+        let worker = shutdown.run_worker(0, async move {
+            let _item = queue.get_item().await.unwrap();
+            panic!("delivery failed");
+        });
+        let result = task::block_on(task::spawn(AssertUnwindSafe(worker).catch_unwind()));
+
+        assert!(result.is_err());
+        assert_eq!(shutdown.take_unfinished().len(), 1);
+        assert!(task::block_on(shutdown.drain(Duration::from_millis(0))));
+    }
+
+    #[test]
+    fn cancelled_workers_are_requeued_after_stopping() {
+        let shutdown = Shutdown::new();
+        let mut queue = queue(&shutdown, 1);
+
+        let worker = task::spawn({
+            let shutdown = shutdown.clone();
+
+            async move {
+                shutdown
+                    .run_worker(0, async move {
+                        let _item = queue.get_item().await.unwrap();
+                        futures::future::pending::<()>().await;
+                    })
+                    .await
+            }
+        });
+
+        task::block_on(async {
+            while shutdown.0.jobs.lock().unwrap().is_empty() {
+                task::sleep(POLL_INTERVAL).await;
+            }
+
+            shutdown.trigger();
+            assert!(!shutdown.drain(Duration::from_millis(0)).await);
+
+            shutdown.cancel_workers().await;
+            assert!(worker.await.is_err());
+        });
+
+        let unfinished = shutdown.take_unfinished();
+        assert_eq!(
+            unfinished,
+            vec![("deliver".to_owned(), "https://example.com/inbox".to_owned())]
+        );
+    }
+}
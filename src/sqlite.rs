@@ -0,0 +1,595 @@
+use crate::memory::parse_cursor;
+use crate::pool::{Manager, Pooled};
+use crate::query;
+use futures::channel::oneshot;
+use kroeg_tap::{
+    CollectionPointer, EntityStore, QuadQuery, QueueItem, QueueStore, StoreError, StoreItem,
+};
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior, NO_PARAMS};
+use serde_json::Value;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const BLOCKING_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    static ref JOBS: Mutex<Sender<Job>> = Mutex::new(spawn_threads());
+}
+
+// SQLite calls block until the file is read or written, so they run on these threads instead of
+//  the threads of the async executor.
+fn spawn_threads() -> Sender<Job> {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for index in 0..BLOCKING_THREADS {
+        let receiver = receiver.clone();
+
+        thread::Builder::new()
+            .name(format!("sqlite-{}", index))
+            .spawn(move || loop {
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+
+                // The caller sees a panicking job as a failed query, and the thread lives on.
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            })
+            .expect("Failed to start SQLite threads");
+    }
+
+    sender
+}
+
+async fn blocking<T: Send + 'static>(
+    job: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, StoreError> {
+    let (reply, response) = oneshot::channel();
+
+    JOBS.lock()
+        .unwrap()
+        .send(Box::new(move || {
+            let _ = reply.send(job());
+        }))
+        .map_err(|_| StoreError::from("the SQLite threads stopped"))?;
+
+    response
+        .await
+        .map_err(|_| StoreError::from("an SQLite query panicked"))
+}
+
+/// Opens connections to a SQLite database file for a `Pool`.
+pub struct SqliteManager(pub String);
+
+#[async_trait::async_trait]
+impl Manager for SqliteManager {
+    type Connection = Mutex<Connection>;
+
+    async fn connect(&self) -> Result<Self::Connection, StoreError> {
+        let path = self.0.clone();
+
+        blocking(move || {
+            let connection = Connection::open(path)?;
+            connection.busy_timeout(Duration::from_secs(5))?;
+
+            Ok(Mutex::new(connection))
+        })
+        .await?
+    }
+
+    async fn check(&self, _: &mut Self::Connection) -> Result<(), StoreError> {
+        // Connections to a local file don't break while idle.
+        Ok(())
+    }
+}
+
+/// An entity and queue store backed by a SQLite database file. The entity store and queue store
+///  handed out by one lease share the same pooled connection.
+#[derive(Clone)]
+pub struct SqliteStore(Arc<Pooled<SqliteManager>>);
+
+impl SqliteStore {
+    pub fn new(connection: Pooled<SqliteManager>) -> SqliteStore {
+        SqliteStore(Arc::new(connection))
+    }
+
+    // Runs `query` with this lease's connection on one of the SQLite threads.
+    async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    ) -> Result<T, StoreError> {
+        let lease = self.0.clone();
+
+        blocking(move || query(&mut lease.lock().unwrap())).await?
+    }
+
+    /// Counts the items waiting in the delivery queue.
+    pub async fn queue_depth(&self) -> Result<u64, StoreError> {
+        self.run(|connection| {
+            let count: i64 =
+                connection.query_row("SELECT count(*) FROM queue_items", NO_PARAMS, |row| {
+                    row.get(0)
+                })?;
+
+            Ok(count as u64)
+        })
+        .await
+    }
+
+    /// Returns the event and data of up to `limit` queue items, oldest first, without taking them.
+    pub async fn queue_items(&self, limit: u32) -> Result<Vec<(String, String)>, StoreError> {
+        self.run(move |connection| {
+            let mut statement = connection
+                .prepare("SELECT event, data FROM queue_items ORDER BY id ASC LIMIT ?1")?;
+            let items =
+                statement.query_map(params![limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+            Ok(items.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+}
+
+pub struct SqliteQueueItem {
+    event: String,
+    data: String,
+}
+
+impl QueueItem for SqliteQueueItem {
+    fn event(&self) -> &str {
+        &self.event
+    }
+
+    fn data(&self) -> &str {
+        &self.data
+    }
+}
+
+fn parse_item(id: &str, data: &str) -> Result<StoreItem, StoreError> {
+    let json: Value = serde_json::from_str(data)?;
+
+    StoreItem::parse(id, &json)
+        .map_err(|e| format!("failed to parse stored entity {}: {:?}", id, e).into())
+}
+
+fn read_items(
+    connection: &Connection,
+    sql: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> Result<Vec<(i64, String)>, StoreError> {
+    let mut statement = connection.prepare(sql)?;
+    let rows = statement.query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+#[async_trait::async_trait]
+impl EntityStore for SqliteStore {
+    async fn get(&mut self, path: String, _local: bool) -> Result<Option<StoreItem>, StoreError> {
+        self.run(move |connection| {
+            let data: Option<String> = connection
+                .query_row(
+                    "SELECT data FROM entities WHERE id = ?1",
+                    params![path],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            match data {
+                Some(data) => Ok(Some(parse_item(&path, &data)?)),
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+
+    async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
+        let data = item.to_json().to_string();
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO entities (id, data) VALUES (?1, ?2)",
+                params![path, data],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
+        self.run(move |connection| {
+            // Only documents that mention one of the query's terms can match it.
+            let terms = query::search_terms(&query);
+            let sql = match &terms {
+                Some(terms) if terms.is_empty() => {
+                    return Ok(query::evaluate(std::iter::empty(), &query))
+                }
+                Some(terms) => format!(
+                    "SELECT data FROM entities WHERE {}",
+                    (1..=terms.len())
+                        .map(|index| format!("instr(data, ?{}) > 0", index))
+                        .collect::<Vec<_>>()
+                        .join(" OR ")
+                ),
+                None => "SELECT data FROM entities".to_owned(),
+            };
+
+            let mut statement = connection.prepare(&sql)?;
+            let documents = statement
+                .query_map(terms.iter().flatten(), |row| row.get::<_, String>(0))?
+                .map(|data| Ok(serde_json::from_str(&data?)?))
+                .collect::<Result<Vec<Value>, StoreError>>()?;
+
+            Ok(query::evaluate(&documents, &query))
+        })
+        .await
+    }
+
+    async fn read_collection(
+        &mut self,
+        path: String,
+        count: Option<u32>,
+        cursor: Option<String>,
+    ) -> Result<CollectionPointer, StoreError> {
+        let limit = count.unwrap_or(20) as i64;
+
+        self.run(move |connection| {
+            // Collections are read newest-first, like the cellar backend does.
+            let items = match cursor.as_ref().and_then(|cursor| parse_cursor(cursor)) {
+                Some((true, id)) => read_items(
+                    connection,
+                    "SELECT id, object FROM collection_items WHERE collection = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+                    params![path, id as i64, limit],
+                )?,
+                Some((false, id)) => {
+                    let mut items = read_items(
+                        connection,
+                        "SELECT id, object FROM collection_items WHERE collection = ?1 AND id > ?2 ORDER BY id ASC LIMIT ?3",
+                        params![path, id as i64, limit],
+                    )?;
+                    items.reverse();
+                    items
+                }
+                None => read_items(
+                    connection,
+                    "SELECT id, object FROM collection_items WHERE collection = ?1 ORDER BY id DESC LIMIT ?2",
+                    params![path, limit],
+                )?,
+            };
+
+            let total: i64 = connection.query_row(
+                "SELECT COUNT(*) FROM collection_items WHERE collection = ?1",
+                params![path],
+                |row| row.get(0),
+            )?;
+
+            Ok(CollectionPointer {
+                after: items.first().map(|(id, _)| format!("after-{}", id)),
+                before: items.last().map(|(id, _)| format!("before-{}", id)),
+                items: items.into_iter().map(|(_, item)| item).collect(),
+                count: Some(total as u32),
+            })
+        })
+        .await
+    }
+
+    async fn find_collection(
+        &mut self,
+        path: String,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        self.run(move |connection| {
+            let found: Option<i64> = connection
+                .query_row(
+                    "SELECT id FROM collection_items WHERE collection = ?1 AND object = ?2",
+                    params![path, item],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            Ok(CollectionPointer {
+                items: found.iter().map(|_| item.to_owned()).collect(),
+                after: found.map(|id| format!("after-{}", id)),
+                before: found.map(|id| format!("before-{}", id)),
+                count: None,
+            })
+        })
+        .await
+    }
+
+    async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO collection_items (collection, object) VALUES (?1, ?2)",
+                params![path, item],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn read_collection_inverse(
+        &mut self,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        self.run(move |connection| {
+            let items = read_items(
+                connection,
+                "SELECT id, collection FROM collection_items WHERE object = ?1 ORDER BY id DESC",
+                params![item],
+            )?;
+
+            Ok(CollectionPointer {
+                count: Some(items.len() as u32),
+                items: items
+                    .into_iter()
+                    .map(|(_, collection)| collection)
+                    .collect(),
+                after: None,
+                before: None,
+            })
+        })
+        .await
+    }
+
+    async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM collection_items WHERE collection = ?1 AND object = ?2",
+                params![path, item],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl QueueStore for SqliteStore {
+    async fn get_item(&mut self) -> Result<Option<Box<dyn QueueItem + Send>>, StoreError> {
+        let item = self
+            .run(|connection| {
+                // Other processes may share the file, so the item is taken under a write lock.
+                let transaction =
+                    connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let item: Option<(i64, String, String)> = transaction
+                    .query_row(
+                        "SELECT id, event, data FROM queue_items ORDER BY id ASC LIMIT 1",
+                        NO_PARAMS,
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )
+                    .optional()?;
+
+                if let Some((id, _, _)) = &item {
+                    transaction.execute("DELETE FROM queue_items WHERE id = ?1", params![id])?;
+                }
+
+                transaction.commit()?;
+
+                Ok(item)
+            })
+            .await?;
+
+        Ok(item.map(|(_, event, data)| {
+            Box::new(SqliteQueueItem { event, data }) as Box<dyn QueueItem + Send>
+        }))
+    }
+
+    async fn mark_success(&mut self, _item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn mark_failure(&mut self, item: Box<dyn QueueItem + Send>) -> Result<(), StoreError> {
+        self.add(item.event().to_owned(), item.data().to_owned())
+            .await
+    }
+
+    async fn add(&mut self, event: String, data: String) -> Result<(), StoreError> {
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO queue_items (event, data) VALUES (?1, ?2)",
+                params![event, data],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PoolConfig;
+    use crate::pool::Pool;
+    use async_std::task::block_on;
+    use kroeg_tap::{QueryId, QueryObject};
+    use serde_json::json;
+    use std::collections::HashSet;
+    use std::thread;
+
+    const NOTE: &str = "https://www.w3.org/ns/activitystreams#Note";
+
+    fn database(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("kroeg-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let path = path.to_str().unwrap().to_owned();
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(include_str!("../migrations/sqlite/0001_initial.sql"))
+            .unwrap();
+
+        path
+    }
+
+    fn pool(path: &str) -> Pool<SqliteManager> {
+        Pool::new(
+            SqliteManager(path.to_owned()),
+            PoolConfig {
+                max_connections: 1,
+                min_idle: 0,
+                idle_timeout: 600,
+                acquire_timeout: 1,
+            },
+        )
+    }
+
+    fn open(pool: &Pool<SqliteManager>) -> SqliteStore {
+        SqliteStore::new(block_on(pool.get()).unwrap())
+    }
+
+    fn note(id: &str, content: &str) -> StoreItem {
+        StoreItem::parse(
+            id,
+            &json!([{
+                "@id": id,
+                "@type": [NOTE],
+                "https://www.w3.org/ns/activitystreams#content": [{ "@value": content }],
+            }]),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn stores_entities_and_collections() {
+        let mut store = open(&pool(&database("entities")));
+
+        block_on(async {
+            let id = "https://example.com/notes/1".to_owned();
+            store
+                .put(id.to_owned(), &mut note(&id, "hi"))
+                .await
+                .unwrap();
+            assert_eq!(
+                store.get(id.to_owned(), true).await.unwrap().unwrap().id(),
+                id
+            );
+
+            let outbox = "https://example.com/outbox".to_owned();
+            for index in 0..3 {
+                let item = format!("https://example.com/notes/{}", index);
+                store
+                    .insert_collection(outbox.to_owned(), item)
+                    .await
+                    .unwrap();
+            }
+
+            let page = store
+                .read_collection(outbox.to_owned(), Some(2), None)
+                .await
+                .unwrap();
+            assert_eq!(page.count, Some(3));
+            assert_eq!(
+                page.items,
+                vec!["https://example.com/notes/2", "https://example.com/notes/1"]
+            );
+        });
+    }
+
+    #[test]
+    fn queries_only_matching_documents() {
+        let mut store = open(&pool(&database("query")));
+
+        block_on(async {
+            for (id, content) in &[
+                ("https://example.com/notes/1", "hello"),
+                ("https://example.com/notes/2", "bye"),
+            ] {
+                store
+                    .put(id.to_string(), &mut note(id, content))
+                    .await
+                    .unwrap();
+            }
+
+            let rows = store
+                .query(vec![QuadQuery(
+                    QueryId::Placeholder(0),
+                    QueryId::Value("https://www.w3.org/ns/activitystreams#content".to_owned()),
+                    QueryObject::Object {
+                        value: "bye".to_owned(),
+                        type_id: QueryId::Ignore,
+                    },
+                )])
+                .await
+                .unwrap();
+
+            assert_eq!(rows, vec![vec!["https://example.com/notes/2".to_owned()]]);
+        });
+    }
+
+    #[test]
+    fn hands_out_each_queue_item_once() {
+        let path = database("queue");
+        let mut store = open(&pool(&path));
+
+        block_on(async {
+            for index in 0..100 {
+                store
+                    .add("deliver".to_owned(), index.to_string())
+                    .await
+                    .unwrap();
+            }
+        });
+        assert_eq!(block_on(store.queue_depth()).unwrap(), 100);
+
+        // Every worker leases its own connection, so these compete through the file.
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let path = path.to_owned();
+
+                thread::spawn(move || {
+                    let mut store = open(&pool(&path));
+                    let mut taken = Vec::new();
+
+                    while let Some(item) = block_on(store.get_item()).unwrap() {
+                        taken.push(item.data().to_owned());
+                    }
+
+                    taken
+                })
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        for worker in workers {
+            for data in worker.join().unwrap() {
+                assert!(seen.insert(data), "queue item handed out twice");
+            }
+        }
+
+        assert_eq!(seen.len(), 100);
+        assert_eq!(block_on(store.queue_depth()).unwrap(), 0);
+    }
+
+    #[test]
+    fn reuses_pooled_connections() {
+        let pool = pool(&database("reuse"));
+
+        // Temporary tables only exist on the connection that created them.
+        let store = open(&pool);
+        block_on(store.run(|connection| {
+            connection.execute_batch("CREATE TEMP TABLE leased (id INTEGER)")?;
+            Ok(())
+        }))
+        .unwrap();
+        drop(store);
+
+        let store = open(&pool);
+        block_on(store.run(|connection| {
+            connection.execute_batch("SELECT id FROM leased")?;
+            Ok(())
+        }))
+        .unwrap();
+    }
+}
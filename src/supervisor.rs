@@ -0,0 +1,136 @@
+use crate::database::DatabasePool;
+use crate::logging;
+use crate::shutdown::{self, Shutdown};
+use futures::future::FutureExt;
+use kroeg_server::{config::ServerConfig, launch_delivery};
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// A worker that ran at least this long is considered healthy, and restarts without delay again.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Keeps track of how many delivery workers are running.
+#[derive(Clone, Default)]
+pub struct WorkerStatus {
+    live: Arc<AtomicUsize>,
+    total: usize,
+}
+
+impl WorkerStatus {
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    fn log(&self) {
+        log::info!(
+            "{} of {} delivery workers running",
+            self.live(),
+            self.total()
+        );
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+async fn supervise(
+    id: usize,
+    pool: DatabasePool,
+    config: ServerConfig,
+    status: WorkerStatus,
+    shutdown: Shutdown,
+) {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        status.live.fetch_add(1, Ordering::SeqCst);
+        let started = Instant::now();
+
+        // Each delivery sets its own request ID within this scope, see `TracedStore`.
+        let worker = logging::scope(None, launch_delivery(pool.clone(), config.clone()));
+        let result = AssertUnwindSafe(shutdown.run_worker(id, worker))
+            .catch_unwind()
+            .await;
+
+        status.live.fetch_sub(1, Ordering::SeqCst);
+        if shutdown.is_stopping() {
+            return;
+        }
+
+        // While still running, the job a worker dropped goes straight back into the queue. During
+        //  a shutdown, `serve` requeues them all at once.
+        if let Err(e) = shutdown::requeue(&pool, shutdown.take_unfinished()).await {
+            log::error!("Failed to requeue the job of delivery worker {}: {}", id, e);
+        }
+
+        match result {
+            Ok(Err(_)) => return,
+            Ok(Ok(result)) => log::error!("Delivery worker {} exited: {:?}", id, result),
+            Err(panic) => log::error!(
+                "Delivery worker {} panicked: {}",
+                id,
+                panic_message(&*panic)
+            ),
+        }
+
+        status.log();
+
+        if started.elapsed() >= STABLE_AFTER {
+            backoff = MIN_BACKOFF;
+        }
+
+        log::info!("Restarting delivery worker {} in {:?}", id, backoff);
+        async_std::task::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+
+        if shutdown.is_stopping() {
+            return;
+        }
+    }
+}
+
+/// Starts `count` delivery workers, each restarted with exponential backoff if it panics or exits.
+pub fn spawn_workers(
+    count: usize,
+    pool: DatabasePool,
+    config: ServerConfig,
+    shutdown: Shutdown,
+) -> WorkerStatus {
+    let status = WorkerStatus {
+        live: Arc::new(AtomicUsize::new(0)),
+        total: count,
+    };
+
+    for id in 0..count {
+        async_std::task::spawn(supervise(
+            id,
+            pool.clone(),
+            config.clone(),
+            status.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    if count > 0 {
+        log::info!("Started {} delivery workers", count);
+    }
+
+    status
+}
@@ -0,0 +1,233 @@
+use crate::config::TlsConfig;
+use crate::listener::{AcceptBackoff, Connection, Listener};
+use crate::shutdown::Shutdown;
+use async_tls::{server::TlsStream, TlsAcceptor};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Clients that don't finish the handshake in time are dropped, so idle connections can't pile up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type TlsError = Box<dyn Error + Send + Sync>;
+
+fn reader(path: &str) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("{}: {}", path, e).into())
+}
+
+/// Reads the certificate chain and private key, and builds an acceptor from them.
+pub fn load(config: &TlsConfig) -> Result<TlsAcceptor, TlsError> {
+    let chain = certs(&mut reader(&config.certificate)?)
+        .map_err(|_| format!("{}: invalid certificate", config.certificate))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates found", config.certificate).into());
+    }
+
+    let mut keys = pkcs8_private_keys(&mut reader(&config.private_key)?)
+        .map_err(|_| format!("{}: invalid private key", config.private_key))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut reader(&config.private_key)?)
+            .map_err(|_| format!("{}: invalid private key", config.private_key))?;
+    }
+
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => return Err(format!("{}: no private key found", config.private_key).into()),
+    };
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.set_single_cert(chain, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Accepts connections on `listener` and performs the TLS handshake for each of them, yielding
+///  the connections that complete it along with their peer address. The certificates are read again after a SIGHUP, and the
+///  listener is closed once the shutdown is triggered.
+pub fn incoming(
+    listener: Listener,
+    config: TlsConfig,
+    shutdown: Shutdown,
+) -> Result<UnboundedReceiver<(Option<SocketAddr>, TlsStream<Connection>)>, TlsError> {
+    let mut acceptor = load(&config)?;
+
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGHUP, reload.clone())?;
+
+    let (sender, receiver) = unbounded();
+
+    async_std::task::spawn(async move {
+        let mut backoff = AcceptBackoff::default();
+
+        while let Some(result) = listener.accept_until(&shutdown).await {
+            let stream = match result {
+                Ok(stream) => stream,
+                Err(e) => {
+                    backoff.wait(e).await;
+                    continue;
+                }
+            };
+
+            backoff.reset();
+
+            if reload.swap(false, Ordering::SeqCst) {
+                match load(&config) {
+                    Ok(reloaded) => {
+                        acceptor = reloaded;
+                        log::info!("Reloaded TLS certificates");
+                    }
+
+                    Err(e) => log::error!(
+                        "Failed to reload TLS certificates, keeping the old ones: {}",
+                        e
+                    ),
+                }
+            }
+
+            // Handshakes happen on their own task, so one slow client can't hold up the others.
+            let peer = stream.peer_addr();
+            let handshake = acceptor.accept(stream);
+            let sender = sender.clone();
+            async_std::task::spawn(async move {
+                match async_std::future::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.unbounded_send((peer, stream));
+                    }
+
+                    Ok(Err(e)) => log::debug!("TLS handshake failed: {}", e),
+                    Err(_) => log::debug!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::ListenAddress;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::StreamExt;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnector, SslMethod};
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    // Writes a self-signed certificate for localhost and its key to the temp dir.
+    fn self_signed(name: &str) -> TlsConfig {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+
+        let names = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let dir = std::env::temp_dir();
+        let certificate = dir.join(format!("kroeg-{}-{}.crt", name, std::process::id()));
+        let private_key = dir.join(format!("kroeg-{}-{}.key", name, std::process::id()));
+        std::fs::write(&certificate, builder.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&private_key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        TlsConfig {
+            certificate: certificate.to_str().unwrap().to_owned(),
+            private_key: private_key.to_str().unwrap().to_owned(),
+        }
+    }
+
+    #[test]
+    fn completes_handshakes() {
+        let config = self_signed("tls-handshake");
+        let shutdown = Shutdown::new();
+
+        async_std::task::block_on(async {
+            let address = ListenAddress::Tcp("127.0.0.1:0".parse().unwrap());
+            let listener = Listener::bind(&address, None).await.unwrap();
+            let address = match &listener {
+                Listener::Tcp(listener) => listener.local_addr().unwrap(),
+                Listener::Unix(..) => unreachable!(),
+            };
+
+            let mut incoming = incoming(listener, config.clone(), shutdown.clone()).unwrap();
+
+            // A client that never starts its handshake doesn't hold up the next one.
+            let idle = TcpStream::connect(address).unwrap();
+
+            let client = thread::spawn(move || {
+                let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+                connector.set_ca_file(&config.certificate).unwrap();
+
+                let stream = TcpStream::connect(address).unwrap();
+                let mut stream = connector.build().connect("localhost", stream).unwrap();
+                stream.write_all(b"ping").unwrap();
+
+                let mut reply = [0; 4];
+                stream.read_exact(&mut reply).unwrap();
+                reply
+            });
+
+            let (peer, mut stream) = incoming.next().await.unwrap();
+            assert_eq!(peer.unwrap().ip(), address.ip());
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"ping");
+
+            stream.write_all(b"pong").await.unwrap();
+            stream.flush().await.unwrap();
+            assert_eq!(&client.join().unwrap(), b"pong");
+
+            // Once the idle client is gone too, nothing is left to yield after the shutdown.
+            drop(idle);
+            shutdown.trigger();
+            assert!(incoming.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn rejects_missing_keys() {
+        let config = TlsConfig {
+            private_key: "/nonexistent/kroeg.key".to_owned(),
+            ..self_signed("tls-missing-key")
+        };
+
+        assert!(load(&config).is_err());
+    }
+}
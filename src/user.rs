@@ -1,4 +1,5 @@
 use crate::config::KroegConfig;
+use crate::database::DatabasePool;
 use clap::ArgMatches;
 use jsonld::nodemap::{Pointer, Value};
 use kroeg_server::{config::ServerConfig, LeasedConnection, StorePool};
@@ -10,17 +11,24 @@ use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, sign::Signer};
 use serde_json::{json, Value as JValue};
 use std::collections::HashMap;
 
-async fn create_auth(store: &mut dyn EntityStore, id: String) -> Result<(), StoreError> {
-    let person = store.get(id, false).await?.unwrap();
+/// Signs a bearer token for the actor `id`, using the actor's own key.
+pub async fn create_auth(store: &mut dyn EntityStore, id: String) -> Result<String, StoreError> {
+    let person = match store.get(id.to_owned(), false).await? {
+        Some(person) => person,
+        None => return Err(format!("actor {} does not exist", id).into()),
+    };
 
     let keyid = if let [Pointer::Id(id)] = &person.main()[sec!(publicKey)] as &[_] {
         id.to_owned()
     } else {
-        eprintln!("Cannot create authentication for user: no key");
-        return Ok(());
+        return Err("Cannot create authentication for user: no key".into());
+    };
+
+    let mut key = match store.get(keyid.to_owned(), false).await? {
+        Some(key) => key,
+        None => return Err(format!("key {} does not exist", keyid).into()),
     };
 
-    let mut key = store.get(keyid.to_owned(), false).await?.unwrap();
     let private = if let [Pointer::Value(Value {
         value: JValue::String(strval),
         ..
@@ -28,8 +36,7 @@ async fn create_auth(store: &mut dyn EntityStore, id: String) -> Result<(), Stor
     {
         PKey::from_rsa(Rsa::private_key_from_pem(strval.as_bytes())?)?
     } else {
-        eprintln!("Cannot create authentication for user: no private key");
-        return Ok(());
+        return Err("Cannot create authentication for user: no private key".into());
     };
 
     let mut signer = Signer::new(MessageDigest::sha256(), &private).unwrap();
@@ -60,18 +67,18 @@ async fn create_auth(store: &mut dyn EntityStore, id: String) -> Result<(), Stor
     signer.update(signed.as_bytes()).unwrap();
     let signature = base64::encode_config(&signer.sign_to_vec().unwrap(), base64::URL_SAFE_NO_PAD);
 
-    println!("{}.{}", signed, signature);
-
-    Ok(())
+    Ok(format!("{}.{}", signed, signature))
 }
 
-async fn create_actor(
-    config: ServerConfig,
+/// Creates a local actor, with a key pair and the usual collections.
+pub async fn create_actor(
+    config: &ServerConfig,
     store: &mut dyn EntityStore,
     queue: &mut dyn QueueStore,
     mut id: String,
-    cmd: &ArgMatches<'_>,
-) {
+    username: Option<&str>,
+    name: Option<&str>,
+) -> Result<(), StoreError> {
     let mut context = Context {
         user: User {
             claims: HashMap::new(),
@@ -94,20 +101,20 @@ async fn create_actor(
         "@type": [as2!(Person)],
     });
 
-    if let Some(item) = cmd.value_of("username") {
+    if let Some(item) = username {
         json.as_object_mut().unwrap().insert(
             as2!(preferredUsername).to_owned(),
             json!([{ "@value": item }]),
         );
     }
 
-    if let Some(item) = cmd.value_of("name") {
+    if let Some(item) = name {
         json.as_object_mut()
             .unwrap()
             .insert(as2!(name).to_owned(), json!([{ "@value": item }]));
     }
 
-    let untangled = untangle(&json).unwrap();
+    let untangled = untangle(&json).map_err(|e| format!("failed to untangle: {:?}", e))?;
     for (key, mut value) in untangled {
         value.meta()[kroeg!(instance)].push(Pointer::Value(Value {
             value: context.instance_id.into(),
@@ -115,28 +122,38 @@ async fn create_actor(
             language: None,
         }));
 
-        context.entity_store.put(key, &mut value).await.unwrap();
+        context.entity_store.put(key, &mut value).await?;
     }
 
     CreateActorHandler
         .handle(&mut context, &mut "".to_string(), &mut id)
-        .await
-        .unwrap();
+        .await?;
 
-    println!("done");
+    Ok(())
 }
 
 pub async fn handle(config: KroegConfig, matches: &ArgMatches<'_>) {
     let id = matches.value_of("ACTOR").unwrap().to_owned();
-    let pool = crate::DatabasePool(config.database);
+    let pool = DatabasePool::new(config.database);
     let mut conn = pool.connect().await.expect("Database connection failed");
 
     let (entity_store, queue_store) = conn.get();
 
     match matches.subcommand() {
-        ("token", _) => create_auth(entity_store, id).await.unwrap(),
+        ("token", _) => println!("{}", create_auth(entity_store, id).await.unwrap()),
         ("create", Some(cmd)) => {
-            create_actor(config.server, entity_store, queue_store, id, cmd).await
+            create_actor(
+                &config.server,
+                entity_store,
+                queue_store,
+                id,
+                cmd.value_of("username"),
+                cmd.value_of("name"),
+            )
+            .await
+            .unwrap();
+
+            println!("done");
         }
         _ => unreachable!(),
     }